@@ -0,0 +1,163 @@
+//! 复制的键值存储: 一个可执行文件, 既是节点也是命令行客户端.
+//!
+//! 在本机起三个节点, 各开一个终端:
+//!
+//! ```text
+//! cargo run --example kv -- server examples/kv/node1.yaml
+//! cargo run --example kv -- server examples/kv/node2.yaml
+//! cargo run --example kv -- server examples/kv/node3.yaml
+//! ```
+//!
+//! 然后用客户端读写, 默认连三个节点的客户端端口:
+//!
+//! ```text
+//! cargo run --example kv -- put color blue
+//! cargo run --example kv -- get color
+//! cargo run --example kv -- scan col
+//! cargo run --example kv -- delete color
+//! cargo run --example kv -- status
+//! ```
+//!
+//! 试故障转移: 停掉status里的leader(Ctrl-C会先转移leader, 再按一次直接退出), 剩下两个节点选出新leader后,
+//! 客户端自动找到它. 示例配置的tick是100毫秒, 选举不到一秒, 客户端默认最多等10秒
+
+mod store;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use iraft::client::{BlockingClient, ClientConfig};
+use iraft::conf::Config;
+use iraft::server::RaftServer;
+
+use crate::store::{KvCommand, KvQuery, KvStore};
+
+/// 示例配置里三个节点的客户端地址
+const SERVERS: &str = "127.0.0.1:7101,127.0.0.1:7102,127.0.0.1:7103";
+
+const USAGE: &str = "usage:
+  kv server <config>
+  kv [--servers addr,addr,...] [--timeout secs] <command>
+
+commands:
+  get <key>
+  put <key> <value>
+  delete <key>
+  scan [prefix]
+  status
+  transfer [node id]";
+
+fn main() -> Result<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("server") {
+        let file = args.get(1).ok_or_else(|| anyhow!(USAGE))?;
+        return iraft::runtime::block_on(serve(Config::new(file)?));
+    }
+
+    let mut conf = ClientConfig {
+        seeds: split(SERVERS),
+        ..ClientConfig::default()
+    };
+    //选项在命令前面
+    while args.first().is_some_and(|arg| arg.starts_with("--")) {
+        let option = args.remove(0);
+        if args.is_empty() {
+            bail!(USAGE);
+        }
+        let value = args.remove(0);
+        match option.as_str() {
+            "--servers" => conf.seeds = split(&value),
+            "--timeout" => conf.timeout = Duration::from_secs(value.parse()?),
+            _ => bail!("unknown option {}\n{}", option, USAGE),
+        }
+    }
+    let seeds = conf.seeds.clone();
+    let client = BlockingClient::new(conf)?;
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["get", key] => {
+            let value: Option<String> = read(&client, KvQuery::Get { key: key.to_string() })?;
+            match value {
+                Some(value) => println!("{}", value),
+                None => println!("(not found)"),
+            }
+        }
+        ["put", key, value] => {
+            let previous = propose(&client, KvCommand::Put { key: key.to_string(), value: value.to_string() })?;
+            println!("OK, previous value: {:?}", previous);
+        }
+        ["delete", key] => {
+            let previous = propose(&client, KvCommand::Delete { key: key.to_string() })?;
+            println!("OK, previous value: {:?}", previous);
+        }
+        ["scan"] | ["scan", _] => {
+            let prefix = args.get(1).unwrap_or(&"").to_string();
+            let pairs: Vec<(String, String)> = read(&client, KvQuery::Scan { prefix })?;
+            for (key, value) in pairs {
+                println!("{} = {}", key, value);
+            }
+        }
+        ["status"] => {
+            for addr in seeds.iter() {
+                match client.status(addr) {
+                    Ok(status) => println!(
+                        "{}: node={} role={} term={} leader={:?} commit_index={} applied_index={}",
+                        addr, status.id, status.role, status.term, status.leader, status.commit_index, status.applied_index
+                    ),
+                    Err(e) => println!("{}: {}", addr, e),
+                }
+            }
+        }
+        ["transfer"] => client.transfer_leadership(None)?,
+        ["transfer", target] => client.transfer_leadership(Some(target.to_string()))?,
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+fn split(addrs: &str) -> Vec<String> {
+    addrs.split(',').filter(|addr| !addr.is_empty()).map(String::from).collect()
+}
+
+fn propose(client: &BlockingClient, command: KvCommand) -> Result<Option<String>> {
+    let result = client.propose(bincode::serialize(&command)?)?;
+    Ok(bincode::deserialize(&result)?)
+}
+
+fn read<T: serde::de::DeserializeOwned>(client: &BlockingClient, query: KvQuery) -> Result<T> {
+    let result = client.read(bincode::serialize(&query)?)?;
+    Ok(bincode::deserialize(&result)?)
+}
+
+/// 和iraft本身的节点一样运行, 状态机换成KvStore. 客户端通过listen_client访问
+async fn serve(conf: Config) -> Result<()> {
+    if conf.listen_client.is_none() {
+        bail!("listen_client is required for the kv server");
+    }
+    iraft::logging::init(&conf)?;
+    let server = RaftServer::new(conf, Box::new(KvStore::default())).await?;
+
+    //SIGINT/SIGTERM: 转移leader后停止, 再收到一次直接退出
+    let handle = server.handle();
+    let (signal_tx, mut signal_rx) = futures::channel::mpsc::unbounded();
+    ctrlc::set_handler(move || {
+        if signal_tx.unbounded_send(()).is_err() {
+            std::process::exit(130);
+        }
+    })?;
+    iraft::runtime::spawn(async move {
+        if signal_rx.next().await.is_some() {
+            signal_rx.close();
+            if let Err(e) = handle.shutdown(true).await {
+                log::error!("shutdown failed: {}", e);
+            }
+        }
+    });
+
+    let (_client_tx, client_rx) = futures::channel::mpsc::channel(1);
+    let status = server.serve(client_rx).await?;
+    log::info!("final status: {:?}", status);
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! 键值状态机. 写是提议, 读是线性一致读, 命令和结果都用bincode编码
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use iraft::state::State;
+use serde_derive::{Deserialize, Serialize};
+
+/// 写命令, 结果是key原来的值 Option<String>
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KvCommand {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+/// 读请求. Get的结果是Option<String>, Scan的结果是按key排序的Vec<(String, String)>
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KvQuery {
+    Get { key: String },
+    Scan { prefix: String },
+}
+
+/// 数据只在内存里. 没有快照, 重启后applied_index从0开始, 由节点重新应用整个log恢复
+#[derive(Debug, Default)]
+pub struct KvStore {
+    applied_index: u64,
+    data: BTreeMap<String, String>,
+}
+
+impl State for KvStore {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn apply(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.applied_index = index;
+        let previous = match bincode::deserialize(&command)? {
+            KvCommand::Put { key, value } => self.data.insert(key, value),
+            KvCommand::Delete { key } => self.data.remove(&key),
+        };
+        Ok(bincode::serialize(&previous)?)
+    }
+
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        match bincode::deserialize(&query)? {
+            KvQuery::Get { key } => Ok(bincode::serialize(&self.data.get(&key))?),
+            KvQuery::Scan { prefix } => {
+                let pairs: Vec<(&String, &String)> = self.data.range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .collect();
+                Ok(bincode::serialize(&pairs)?)
+            }
+        }
+    }
+}
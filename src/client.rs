@@ -0,0 +1,483 @@
+//! 客户端库, 通过listen_client端口访问集群, 协议见protocol模块.
+//! 从seeds里找到leader并缓存, 收到NotLeader时换到它告诉的leader; 节点没有执行的请求, 和重复执行也没关系的请求,
+//! 在截止时间之前自动重试. 提议带会话和seq, 重试不会重复应用. 同步接口见BlockingClient
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::{mpsc, oneshot};
+use futures::io::ReadHalf;
+use futures::{AsyncReadExt, StreamExt};
+
+use crate::conf::TlsConfig;
+use crate::log::log::ConfigChange;
+use crate::message::RequestError;
+use crate::node::Status;
+use crate::protocol::{ClientReply, ClientRequest, Operation, Reply};
+use crate::runtime::{self, Runtime};
+use crate::tls::{Stream, Tls};
+use crate::transport::{read_frame, write_frame, Endpoint, Handshake, MAX_FRAME_BYTES};
+
+/// 客户端配置
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// 节点的listen_client地址. 通过它们查到各节点的id, leader要在其中才能找到, 一般列出所有节点
+    pub seeds: Vec<String>,
+    /// 节点配置了TLS时客户端的证书, 由同一个CA签发
+    pub tls: Option<TlsConfig>,
+    /// 不指定截止时间的请求最多等这么久
+    pub timeout: Duration,
+    /// 建立连接和握手的超时, 查询节点状态也用它
+    pub connect_timeout: Duration,
+    /// 找不到leader或者节点暂时不能处理时, 等这么久再试
+    pub retry_interval: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            seeds: vec![],
+            tls: None,
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(2),
+            retry_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+/// 客户端请求失败的原因
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientError {
+    /// 节点返回的错误, 重试也没用或者不能重试
+    Request(RequestError),
+    /// 到截止时间还没有结果, 提议可能已经应用了也可能没有. last是最后一次尝试失败的原因
+    Timeout { last: Option<String> },
+    /// status连不上节点, 或者运维请求发出后连接断了, 结果未知
+    Connection(String),
+    /// 配置不对, 如证书加载失败
+    Config(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(e) => write!(f, "{}", e),
+            ClientError::Timeout { last: Some(last) } => write!(f, "deadline exceeded, last error: {}", last),
+            ClientError::Timeout { last: None } => write!(f, "deadline exceeded"),
+            ClientError::Connection(reason) => write!(f, "connection error: {}", reason),
+            ClientError::Config(reason) => write!(f, "bad client config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// 到一个节点的连接, 由后台任务收发, 回复按请求id交给等待者. 任务结束后发送端关闭
+type Connection = mpsc::UnboundedSender<(ClientRequest, oneshot::Sender<ClientReply>)>;
+
+/// 一次尝试失败后怎么办
+enum Failure {
+    /// 请求没有发出, 或者节点明确没有执行, 可以重试
+    Retry(String),
+    /// 不知道有没有执行, 只有可以重复执行的请求才重试
+    Unknown(ClientError),
+}
+
+/// 集群的客户端, 可以clone到多个任务里使用, clone共用连接, leader缓存和会话
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    conf: ClientConfig,
+    endpoint: Endpoint,
+    next_id: AtomicU64,
+    //从节点状态学到的 节点id -> 客户端地址
+    nodes: Mutex<HashMap<String, String>>,
+    //缓存的leader地址
+    leader: Mutex<Option<String>>,
+    //客户端地址 -> 连接
+    connections: Mutex<HashMap<String, Connection>>,
+    //提议用的会话, 节点只记住最后一个seq的结果, 同时只有一个提议在用它
+    session: futures::lock::Mutex<Option<Session>>,
+}
+
+struct Session {
+    client_id: u64,
+    seq: u64,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("seeds", &self.inner.conf.seeds)
+            .field("leader", &self.leader())
+            .finish()
+    }
+}
+
+impl Client {
+    /// 只检查配置, 第一个请求时才连接节点
+    pub fn new(conf: ClientConfig) -> Result<Client, ClientError> {
+        if conf.seeds.is_empty() {
+            return Err(ClientError::Config("no seed address".to_string()));
+        }
+        let tls = conf.tls.as_ref().map(Tls::load).transpose().map_err(|e| ClientError::Config(e.to_string()))?;
+        Ok(Client {
+            inner: Arc::new(Inner {
+                endpoint: Endpoint::new(Handshake::client(), tls),
+                conf,
+                next_id: AtomicU64::new(0),
+                nodes: Mutex::new(HashMap::new()),
+                leader: Mutex::new(None),
+                connections: Mutex::new(HashMap::new()),
+                session: futures::lock::Mutex::new(None),
+            }),
+        })
+    }
+
+    /// 缓存的leader地址
+    pub fn leader(&self) -> Option<String> {
+        self.inner.leader.lock().unwrap().clone()
+    }
+
+    /// 提交命令, 返回状态机的结果, 最多等配置的timeout
+    pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+        self.propose_by(command, Instant::now() + self.inner.conf.timeout).await
+    }
+
+    /// 提交命令, deadline之前没有结果返回Timeout. 命令带着会话的seq, 重试多少次也只应用一次.
+    /// 同一个Client上的提议依次执行, 要并发提交就用多个Client. deadline要比节点的会话过期时间短
+    pub async fn propose_by(&self, command: Vec<u8>, deadline: Instant) -> Result<Vec<u8>, ClientError> {
+        let mut session = self.inner.session.lock().await;
+        loop {
+            let s = match session.as_mut() {
+                Some(s) => s,
+                None => {
+                    let client_id = self.register(deadline).await?;
+                    session.insert(Session { client_id, seq: 0 })
+                }
+            };
+            s.seq += 1;
+            let op = Operation::SessionPropose { client_id: s.client_id, seq: s.seq, command: command.clone() };
+            match self.call(op, deadline, true).await {
+                Ok(Reply::Value(value)) => return Ok(value),
+                Ok(reply) => return Err(unexpected(reply)),
+                //过期的会话上什么也不会应用, 之前的尝试如果应用过会话就不会过期, 换个会话重新提交是安全的
+                Err(ClientError::Request(RequestError::SessionExpired)) => *session = None,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 线性一致读, 最多等配置的timeout
+    pub async fn read(&self, query: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+        self.read_by(query, Instant::now() + self.inner.conf.timeout).await
+    }
+
+    /// 线性一致读, deadline之前没有结果返回Timeout
+    pub async fn read_by(&self, query: Vec<u8>, deadline: Instant) -> Result<Vec<u8>, ClientError> {
+        match self.call(Operation::Read(query), deadline, true).await? {
+            Reply::Value(value) => Ok(value),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// 把leader转移给target, None时由leader选. 请求发出后连接断了不重试
+    pub async fn transfer_leadership(&self, target: Option<String>) -> Result<(), ClientError> {
+        let deadline = Instant::now() + self.inner.conf.timeout;
+        self.call(Operation::TransferLeadership(target), deadline, false).await?;
+        //leader换了
+        *self.inner.leader.lock().unwrap() = None;
+        Ok(())
+    }
+
+    /// 增删一个节点. 请求发出后连接断了不重试
+    pub async fn change_membership(&self, change: ConfigChange) -> Result<(), ClientError> {
+        let deadline = Instant::now() + self.inner.conf.timeout;
+        self.call(Operation::ChangeMembership(change), deadline, false).await.map(|_| ())
+    }
+
+    /// 查询addr上节点的状态, 不需要leader
+    pub async fn status(&self, addr: &str) -> Result<Status, ClientError> {
+        let reply = runtime::timeout(self.inner.conf.connect_timeout, self.send(addr, Operation::Status)).await
+            .map_err(|_| ClientError::Timeout { last: Some(format!("no reply from {}", addr)) })?;
+        match reply {
+            Ok(ClientReply { result: Ok(Reply::Status(status)), .. }) => Ok(*status),
+            Ok(ClientReply { result: Ok(reply), .. }) => Err(unexpected(reply)),
+            Ok(ClientReply { result: Err(e), .. }) => Err(ClientError::Request(e)),
+            Err(Failure::Retry(reason)) => Err(ClientError::Connection(reason)),
+            Err(Failure::Unknown(e)) => Err(e),
+        }
+    }
+
+    async fn register(&self, deadline: Instant) -> Result<u64, ClientError> {
+        match self.call(Operation::RegisterSession, deadline, true).await? {
+            Reply::Session(client_id) => Ok(client_id),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// 发给leader, 没有执行的重试; idempotent的请求重复执行没有副作用, 结果未知时也重试
+    async fn call(&self, op: Operation, deadline: Instant, idempotent: bool) -> Result<Reply, ClientError> {
+        let mut last = None;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ClientError::Timeout { last });
+            }
+            let addr = match self.leader() {
+                Some(addr) => addr,
+                None => match self.discover(deadline).await {
+                    Some(addr) => addr,
+                    None => {
+                        last = Some("no leader found".to_string());
+                        self.pause(deadline).await;
+                        continue;
+                    }
+                },
+            };
+            let reply = match runtime::timeout(deadline - now, self.send(&addr, op.clone())).await {
+                Ok(reply) => reply,
+                Err(_) => return Err(ClientError::Timeout { last: Some(format!("no reply from {}", addr)) }),
+            };
+            let failure = match reply {
+                Ok(ClientReply { result: Ok(reply), .. }) => return Ok(reply),
+                Ok(ClientReply { result: Err(e), .. }) => match e {
+                    RequestError::NotLeader { ref leader } => {
+                        self.follow(&addr, leader.as_deref());
+                        Failure::Retry(e.to_string())
+                    }
+                    RequestError::Overloaded | RequestError::Unavailable(_) => Failure::Retry(e.to_string()),
+                    RequestError::LeadershipLost | RequestError::Timeout | RequestError::Stopped => {
+                        self.forget(&addr);
+                        Failure::Unknown(ClientError::Request(e))
+                    }
+                    e => return Err(ClientError::Request(e)),
+                },
+                Err(failure) => failure,
+            };
+            last = Some(match failure {
+                Failure::Retry(reason) => reason,
+                Failure::Unknown(e) if idempotent => e.to_string(),
+                Failure::Unknown(e) => return Err(e),
+            });
+            //还是同一个节点的话等一会儿, 换了leader马上重试
+            if self.leader().as_deref() == Some(addr.as_str()) || self.leader().is_none() {
+                self.pause(deadline).await;
+            }
+        }
+    }
+
+    /// 在addr上执行一次请求
+    async fn send(&self, addr: &str, op: Operation) -> Result<ClientReply, Failure> {
+        let connection = self.connection(addr).await.map_err(|e| {
+            self.forget(addr);
+            Failure::Retry(format!("connect to {}: {}", addr, e))
+        })?;
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        if connection.unbounded_send((ClientRequest { id, op }, tx)).is_err() {
+            self.disconnect(addr);
+            return Err(Failure::Retry(format!("connection to {} closed", addr)));
+        }
+        rx.await.map_err(|_| {
+            self.disconnect(addr);
+            self.forget(addr);
+            Failure::Unknown(ClientError::Connection(format!("connection to {} closed before reply", addr)))
+        })
+    }
+
+    /// 到addr的连接, 没有或者已经断开时新建
+    async fn connection(&self, addr: &str) -> anyhow::Result<Connection> {
+        if let Some(connection) = self.inner.connections.lock().unwrap().get(addr) {
+            if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+        let connect = async {
+            let stream = runtime::connect(addr).await?;
+            self.inner.endpoint.connect(stream, addr, None).await
+        };
+        let (stream, _) = runtime::timeout(self.inner.conf.connect_timeout, connect).await??;
+        let (tx, rx) = mpsc::unbounded();
+        runtime::spawn(run_connection(stream, rx));
+        self.inner.connections.lock().unwrap().insert(addr.to_string(), tx.clone());
+        Ok(tx)
+    }
+
+    fn disconnect(&self, addr: &str) {
+        self.inner.connections.lock().unwrap().remove(addr);
+    }
+
+    /// addr上的节点不是leader了
+    fn forget(&self, addr: &str) {
+        let mut leader = self.inner.leader.lock().unwrap();
+        if leader.as_deref() == Some(addr) {
+            *leader = None;
+        }
+    }
+
+    /// addr上的节点说leader是leader_id, 不知道它的地址时留给discover去查
+    fn follow(&self, addr: &str, leader_id: Option<&str>) {
+        let leader_addr = leader_id.and_then(|id| self.inner.nodes.lock().unwrap().get(id).cloned());
+        match leader_addr {
+            Some(leader_addr) if leader_addr != addr => *self.inner.leader.lock().unwrap() = Some(leader_addr),
+            _ => self.forget(addr),
+        }
+    }
+
+    /// 查询seeds和已知节点的状态, 记下各节点的id, 找出任期最新的leader
+    async fn discover(&self, deadline: Instant) -> Option<String> {
+        let mut addrs = self.inner.conf.seeds.clone();
+        for addr in self.inner.nodes.lock().unwrap().values() {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        let timeout = self.inner.conf.connect_timeout.min(deadline.saturating_duration_since(Instant::now()));
+        let replies = futures::future::join_all(addrs.iter().map(|addr| async move {
+            match runtime::timeout(timeout, self.send(addr, Operation::Status)).await {
+                Ok(Ok(ClientReply { result: Ok(Reply::Status(status)), .. })) => Some((addr.clone(), status)),
+                _ => None,
+            }
+        })).await;
+        let statuses: Vec<(String, Box<Status>)> = replies.into_iter().flatten().collect();
+
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        for (addr, status) in statuses.iter() {
+            nodes.insert(status.id.clone(), addr.clone());
+        }
+        //自己是leader的以它为准, 否则看各节点知道的leader
+        let leader = statuses.iter()
+            .filter_map(|(addr, status)| match status.role.as_str() {
+                "leader" => Some((status.term, 1, addr.clone())),
+                _ => status.leader.as_ref().and_then(|id| nodes.get(id)).map(|addr| (status.term, 0, addr.clone())),
+            })
+            .max()
+            .map(|(_, _, addr)| addr);
+        drop(nodes);
+        *self.inner.leader.lock().unwrap() = leader.clone();
+        leader
+    }
+
+    async fn pause(&self, deadline: Instant) {
+        runtime::sleep(self.inner.conf.retry_interval.min(deadline.saturating_duration_since(Instant::now()))).await;
+    }
+}
+
+fn unexpected(reply: Reply) -> ClientError {
+    ClientError::Request(RequestError::Internal(format!("unexpected reply {:?}", reply)))
+}
+
+/// 连接的后台任务: 发出请求, 把回复交给等待者. 连接断开时丢掉等待者, 它们收到Canceled
+async fn run_connection(stream: Stream, mut requests: mpsc::UnboundedReceiver<(ClientRequest, oneshot::Sender<ClientReply>)>) {
+    let (reader, mut writer) = stream.split();
+    //读帧不能被发送打断, 放在单独的任务里
+    let (reply_tx, mut reply_rx) = mpsc::unbounded();
+    let (task, _reading) = futures::FutureExt::remote_handle(read_replies(reader, reply_tx));
+    runtime::spawn(task);
+
+    let mut pending = HashMap::new();
+    loop {
+        futures::select! {
+            request = requests.next() => match request {
+                Some((request, tx)) => {
+                    pending.insert(request.id, tx);
+                    if let Err(e) = write_frame(&mut writer, &request).await {
+                        log::debug!("client connection closed: {}", e);
+                        return;
+                    }
+                }
+                None => return,
+            },
+            reply = reply_rx.next() => match reply {
+                Some(reply) => {
+                    if let Some(tx) = pending.remove(&reply.id) {
+                        let _ = tx.send(reply);
+                    }
+                }
+                None => return,
+            },
+        }
+    }
+}
+
+async fn read_replies(mut reader: ReadHalf<Stream>, reply_tx: mpsc::UnboundedSender<ClientReply>) {
+    loop {
+        match read_frame::<_, ClientReply>(&mut reader, MAX_FRAME_BYTES).await {
+            Ok(Some(reply)) => {
+                if reply_tx.unbounded_send(reply).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                log::debug!("client connection closed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Client的同步接口, 自带运行时, 不能在异步上下文里调用
+pub struct BlockingClient {
+    client: Client,
+    runtime: Runtime,
+}
+
+impl std::fmt::Debug for BlockingClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BlockingClient").field(&self.client).finish()
+    }
+}
+
+impl BlockingClient {
+    pub fn new(conf: ClientConfig) -> Result<BlockingClient, ClientError> {
+        let runtime = Runtime::new().map_err(|e| ClientError::Config(format!("start runtime: {}", e)))?;
+        Ok(BlockingClient { client: Client::new(conf)?, runtime })
+    }
+
+    /// 缓存的leader地址
+    pub fn leader(&self) -> Option<String> {
+        self.client.leader()
+    }
+
+    /// 见Client::propose
+    pub fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+        self.runtime.block_on(self.client.propose(command))
+    }
+
+    /// 见Client::propose_by
+    pub fn propose_by(&self, command: Vec<u8>, deadline: Instant) -> Result<Vec<u8>, ClientError> {
+        self.runtime.block_on(self.client.propose_by(command, deadline))
+    }
+
+    /// 见Client::read
+    pub fn read(&self, query: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+        self.runtime.block_on(self.client.read(query))
+    }
+
+    /// 见Client::read_by
+    pub fn read_by(&self, query: Vec<u8>, deadline: Instant) -> Result<Vec<u8>, ClientError> {
+        self.runtime.block_on(self.client.read_by(query, deadline))
+    }
+
+    /// 见Client::transfer_leadership
+    pub fn transfer_leadership(&self, target: Option<String>) -> Result<(), ClientError> {
+        self.runtime.block_on(self.client.transfer_leadership(target))
+    }
+
+    /// 见Client::change_membership
+    pub fn change_membership(&self, change: ConfigChange) -> Result<(), ClientError> {
+        self.runtime.block_on(self.client.change_membership(change))
+    }
+
+    /// 见Client::status
+    pub fn status(&self, addr: &str) -> Result<Status, ClientError> {
+        self.runtime.block_on(self.client.status(addr))
+    }
+}
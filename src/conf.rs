@@ -8,10 +8,213 @@ use serde_derive::Deserialize;
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub id: String,
+    /// 集群标识, 同一个集群的节点必须相同, 连接时握手检查, 防止连到别的集群. 不配置时是"iraft"
+    #[serde(default = "default_cluster_id")]
+    pub cluster_id: String,
     pub peers: HashMap<String, String>,
     pub listen_raft: String,
+    /// tick间隔(毫秒). 心跳每个tick发一次, 选举超时是2到5个tick
+    #[serde(default = "default_tick_ms")]
+    pub tick_ms: u64,
+    /// 客户端协议的监听地址, 不配置则只能在进程内通过RaftHandle访问
+    #[serde(default)]
+    pub listen_client: Option<String>,
     pub log_level: String,
+    /// 日志文件名(相对data_dir), 不配置则输出到stderr
+    #[serde(default)]
+    pub log_file: Option<String>,
     pub data_dir: String,
+    /// log和元数据的存储方式, file时存在data_dir下
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// 写入什么时候刷盘
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// Prometheus指标的HTTP监听地址, 不配置则不开启
+    #[serde(default)]
+    pub listen_metrics: Option<String>,
+    /// 各队列的长度限制
+    #[serde(default)]
+    pub queues: QueueConfig,
+    /// leader给peer复制log的流控
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    /// 客户端请求合并
+    #[serde(default)]
+    pub batch: BatchConfig,
+    /// 客户端会话
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// 不是leader时把客户端请求转发给leader
+    #[serde(default)]
+    pub forward: ForwardConfig,
+    /// raft端口和客户端端口的TLS, 不配置则是明文TCP. 不开TLS时握手里的node_id只是对方的自称,
+    /// 知道cluster_id的进程都能冒充任意节点, 只能防止配错地址, 不能防止伪造消息
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// 只在内存里, 重启后丢失
+    #[default]
+    Memory,
+    File,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// 每次写入都fsync
+    Every,
+    /// 一段时间内到达的写入合并成一次fsync
+    #[default]
+    Batch,
+    /// 不fsync, 由操作系统决定什么时候落盘, 进程崩溃不丢数据, 机器掉电会丢. 只用于测试
+    Os,
+}
+
+/// 组提交: batch策略下, 第一个写入之后最多再等max_delay_ms, 合并不超过max_writes次写入,
+/// max_bytes字节的log, 一起fsync. max_delay_ms为0时只合并已经在排队的写入
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    pub policy: SyncPolicy,
+    pub max_delay_ms: u64,
+    pub max_writes: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for SyncConfig {
+    fn default() -> SyncConfig {
+        SyncConfig {
+            policy: SyncPolicy::Batch,
+            max_delay_ms: 0,
+            max_writes: 256,
+            max_bytes: 1 << 20,
+        }
+    }
+}
+
+/// 队列满了之后怎么处理客户端请求
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadPolicy {
+    /// 等待队列有空位, 调用方被阻塞
+    Block,
+    /// 马上返回"overloaded"错误
+    Reject,
+}
+
+/// 队列长度限制. 节点之间的消息队列满了时, 读连接的任务等待(TCP反压);
+/// 发往peer的队列满了时合并心跳, 只丢可以重发的消息, 投票和回复不丢, 转发的请求以Overloaded失败
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// 其他节点发来, 等待event loop处理的消息
+    pub inbound: usize,
+    /// event loop发出, 等待分发给各peer的消息
+    pub outbound: usize,
+    /// 每个peer等待发送的消息, 不能小于replication.max_inflight_msgs
+    pub peer: usize,
+    /// 客户端请求, 包括排队的和已提交还没回复的
+    pub requests: usize,
+    pub overload: OverloadPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            inbound: 1024,
+            outbound: 1024,
+            peer: 1024,
+            requests: 1024,
+            overload: OverloadPolicy::Block,
+        }
+    }
+}
+
+/// 复制log的流控: 一条消息最多带max_msg_bytes字节的log(至少一条), 给每个peer发出还没确认的
+/// 消息不超过max_inflight_msgs条, max_inflight_bytes字节, 慢的follower不会被压垮
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    pub max_msg_bytes: usize,
+    pub max_inflight_msgs: usize,
+    pub max_inflight_bytes: usize,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> ReplicationConfig {
+        ReplicationConfig {
+            max_msg_bytes: 1 << 20,
+            max_inflight_msgs: 256,
+            max_inflight_bytes: 32 << 20,
+        }
+    }
+}
+
+/// 客户端请求合并: 取到一个请求后, 把已经排队的和max_delay_us微秒内到达的请求一起交给节点,
+/// 最多max_size个. leader把它们追加成一次写盘, 一次复制. max_delay_us为0时不等待.
+/// 等待期间event loop照常处理心跳, 复制和存储完成, 窗口只推迟这批请求本身:
+/// 低负载时每个请求多等最多max_delay_us, 换来高负载时更少的写盘和复制消息
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub max_size: usize,
+    pub max_delay_us: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_size: 256,
+            max_delay_us: 0,
+        }
+    }
+}
+
+/// 客户端会话多久没有命令后过期(毫秒, 按log里leader写入的时间算). 各节点在同一条log上判断过期, 必须配置相同
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub timeout_ms: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            timeout_ms: 10 * 60 * 1000,
+        }
+    }
+}
+
+/// 开启后follower把收到的提议和线性一致读转发给leader, 再把leader的回复转给客户端;
+/// 还不知道leader或者leader拒绝时一直重试, 超过timeout_ms还没有结果就失败. 运维请求不转发
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ForwardConfig {
+    pub enabled: bool,
+    pub timeout_ms: u64,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> ForwardConfig {
+        ForwardConfig {
+            enabled: false,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// TLS证书, 都是PEM文件路径. 节点之间双向认证: 双方的证书都要由ca签发,
+/// 节点的证书的subject CN或SAN里要有它的节点id
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
 }
 
 impl Config {
@@ -23,20 +226,57 @@ impl Config {
         let _ = f.read_to_string(&mut s)?;
 
         let conf: Config = serde_yaml::from_str(&s)?;
+        conf.validate()?;
         Ok(conf)
     }
 
-    pub fn default() -> Config {
+    /// 检查配置项之间的约束
+    pub fn validate(&self) -> Result<()> {
+        if self.tick_ms == 0 {
+            return Err(anyhow::anyhow!("tick_ms must be positive"));
+        }
+        //队列放不下一个inflight窗口时, 日志复制还没有回应就会被丢掉
+        if self.queues.peer < self.replication.max_inflight_msgs {
+            return Err(anyhow::anyhow!("queues.peer {} is smaller than replication.max_inflight_msgs {}",
+                self.queues.peer, self.replication.max_inflight_msgs));
+        }
+        Ok(())
+    }
+}
+
+fn default_cluster_id() -> String {
+    "iraft".to_string()
+}
+
+fn default_tick_ms() -> u64 {
+    100
+}
+
+impl Default for Config {
+    fn default() -> Config {
         let mut peer = HashMap::new();
         let x = "2";
         let y = "1";
-        peer.insert(x.clone().to_string(), "127.0.0.1:111".to_owned() + x);
+        peer.insert(x.to_string(), "127.0.0.1:111".to_owned() + x);
         Config {
-            id: y.clone().to_string(),
+            id: y.to_string(),
+            cluster_id: default_cluster_id(),
             peers: peer,
             listen_raft: "127.0.0.1:111".to_owned() + y,
+            tick_ms: default_tick_ms(),
+            listen_client: None,
             log_level: "debug".to_string(),
+            log_file: None,
             data_dir: "/data/iraft".to_owned(),
+            storage: StorageKind::Memory,
+            sync: SyncConfig::default(),
+            listen_metrics: None,
+            queues: QueueConfig::default(),
+            replication: ReplicationConfig::default(),
+            batch: BatchConfig::default(),
+            session: SessionConfig::default(),
+            forward: ForwardConfig::default(),
+            tls: None,
         }
     }
 }
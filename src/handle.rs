@@ -0,0 +1,172 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::channel::oneshot;
+use futures::lock::Mutex;
+use futures::SinkExt;
+
+use crate::conf::{OverloadPolicy, TlsConfig};
+use crate::log::log::ConfigChange;
+use crate::message::{Address, Event, Message, Request, RequestError, Response};
+use crate::metrics::Metrics;
+use crate::node::Status;
+use crate::notify::{Notifier, Subscription};
+use crate::runtime;
+use crate::tls::Tls;
+use crate::transport::{read_frame, write_frame, Endpoint, Handshake, MAX_FRAME_BYTES};
+
+/// 命令最多的字节数, 留出余量保证带着它的复制消息不超过帧的上限
+const MAX_COMMAND_BYTES: usize = MAX_FRAME_BYTES - (64 << 10);
+
+/// 交给node处理的客户端请求, 结果通过tx返回
+pub(crate) type Call = (Request, oneshot::Sender<Response>);
+
+/// 所有句柄共用一个发送端: mpsc::Sender每clone一次就多一个保证的空位, 各自clone会让队列上限失效
+pub(crate) type CallSender = Arc<Mutex<mpsc::Sender<Call>>>;
+
+/// 句柄发给event loop的运维指令, 不受请求队列限制
+#[derive(Debug)]
+pub(crate) enum Instruction {
+    Status(oneshot::Sender<Status>),
+    /// 停止节点, 返回最终状态
+    Shutdown { transfer_leadership: bool, tx: oneshot::Sender<Status> },
+}
+
+/// 运行中节点的句柄, 可以clone到多个任务里使用
+#[derive(Clone, Debug)]
+pub struct RaftHandle {
+    request_tx: CallSender,
+    instruction_tx: UnboundedSender<Instruction>,
+    notifier: Arc<Notifier>,
+    metrics: Arc<Metrics>,
+    overload: OverloadPolicy,
+}
+
+impl RaftHandle {
+    pub(crate) fn new(
+        request_tx: CallSender,
+        instruction_tx: UnboundedSender<Instruction>,
+        notifier: Arc<Notifier>,
+        metrics: Arc<Metrics>,
+        overload: OverloadPolicy,
+    ) -> RaftHandle {
+        RaftHandle { request_tx, instruction_tx, notifier, metrics, overload }
+    }
+
+    /// 订阅节点事件: 角色转换, 任期/leader变化, commit推进, 多数派丢失与恢复.
+    /// 只收到订阅之后发生的事件, 当前状态用status查询
+    pub fn subscribe(&self) -> Subscription {
+        self.notifier.subscribe()
+    }
+
+    /// 提交命令, 等它提交并应用到状态机后返回状态机的结果
+    pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.request(Request::Propose(command)).await
+    }
+
+    /// 注册客户端会话, 返回会话id. 会话不活跃超过session.timeout_ms后过期
+    pub async fn register_session(&self) -> Result<u64> {
+        let id = self.request(Request::RegisterSession).await?;
+        Ok(session_id(&id)?)
+    }
+
+    /// 带会话提交命令. seq从1开始, 每个新命令加1, 超时等结果未知时用同样的seq重试,
+    /// 命令只会应用一次, 重复的请求返回第一次的结果. 只记最后一个seq的结果, 同一会话上不要并发提交
+    pub async fn propose_session(&self, client_id: u64, seq: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.request(Request::SessionPropose { client_id, seq, command }).await
+    }
+
+    /// 线性一致读: 确认自己仍是leader并且状态机已追上后, 在状态机上查询
+    pub async fn read(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        self.request(Request::Read(query)).await
+    }
+
+    /// 查询节点状态
+    pub async fn status(&self) -> Result<Status> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Instruction::Status(tx))?;
+        Ok(rx.await.map_err(|_| RequestError::Stopped)?)
+    }
+
+    /// 把leader转移给target, None时选复制进度最快的peer. 本节点下台后返回
+    pub async fn transfer_leadership(&self, target: Option<String>) -> Result<()> {
+        self.request(Request::TransferLeadership(target)).await.map(|_| ())
+    }
+
+    /// 增删一个节点, 变更提交后返回
+    pub async fn change_membership(&self, change: ConfigChange) -> Result<()> {
+        self.request(Request::ChangeMembership(change)).await.map(|_| ())
+    }
+
+    /// 停止节点: 不再接受客户端请求, transfer_leadership为true且本节点是leader时先把leader转移出去,
+    /// 然后刷盘并关闭与peer的连接. 返回节点的最终状态
+    pub async fn shutdown(&self, transfer_leadership: bool) -> Result<Status> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Instruction::Shutdown { transfer_leadership, tx })?;
+        Ok(rx.await.map_err(|_| RequestError::Stopped)?)
+    }
+
+    /// 失败时返回的错误是RequestError, 调用方可以downcast后区分处理
+    async fn request(&self, request: Request) -> Result<Vec<u8>> {
+        Ok(self.call(request).await?)
+    }
+
+    /// 交给节点处理, 返回带类型的结果. 请求队列满了时按OverloadPolicy等待或者返回Overloaded
+    pub(crate) async fn call(&self, request: Request) -> Response {
+        if let Request::Propose(command) | Request::SessionPropose { command, .. } = &request {
+            if command.len() > MAX_COMMAND_BYTES {
+                let reason = format!("command of {} bytes exceeds limit {}", command.len(), MAX_COMMAND_BYTES);
+                return Err(RequestError::Rejected(reason));
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        let mut request_tx = self.request_tx.lock().await;
+        match self.overload {
+            OverloadPolicy::Block => request_tx.send((request, tx)).await
+                .map_err(|_| RequestError::Stopped)?,
+            OverloadPolicy::Reject => request_tx.try_send((request, tx)).map_err(|e| {
+                if e.is_full() {
+                    self.metrics.requests_overloaded.inc();
+                    RequestError::Overloaded
+                } else {
+                    RequestError::Stopped
+                }
+            })?,
+        }
+        drop(request_tx);
+        self.metrics.request_queue.inc();
+        let response = rx.await;
+        self.metrics.request_queue.dec();
+        response.map_err(|_| RequestError::Stopped)?
+    }
+
+    fn send(&self, instruction: Instruction) -> Result<()> {
+        self.instruction_tx.unbounded_send(instruction)
+            .map_err(|_| RequestError::Stopped.into())
+    }
+}
+
+/// 注册会话的结果是8字节大端的会话id
+pub(crate) fn session_id(bytes: &[u8]) -> std::result::Result<u64, RequestError> {
+    bytes.try_into().map(u64::from_be_bytes)
+        .map_err(|_| RequestError::Internal(format!("bad session id of {} bytes", bytes.len())))
+}
+
+/// 通过raft端口查询远端节点的状态, 节点配置了TLS时要给出客户端证书
+pub async fn fetch_status(addr: &str, tls: Option<&TlsConfig>) -> Result<Status> {
+    let endpoint = Endpoint::new(Handshake::client(), tls.map(Tls::load).transpose()?);
+    let (mut stream, _) = endpoint.connect(runtime::connect(addr).await?, addr, None).await?;
+    write_frame(&mut stream, &Message {
+        term: 0,
+        from: Address::Client,
+        to: Address::Local,
+        event: Event::StatusRequest,
+    }).await?;
+    match read_frame(&mut stream, MAX_FRAME_BYTES).await? {
+        Some(Message { event: Event::StatusResponse { status }, .. }) => Ok(status),
+        Some(msg) => Err(anyhow::anyhow!("unexpected response {:?}", msg.event)),
+        None => Err(anyhow::anyhow!("connection closed by {}", addr)),
+    }
+}
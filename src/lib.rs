@@ -1,9 +1,20 @@
 
+mod peer;
 mod transport;
+mod tls;
+mod service;
+mod session;
 mod store;
-mod log;
+pub mod runtime;
+pub mod log;
 pub mod server;
+pub mod client;
 pub mod conf;
+pub mod handle;
+pub mod logging;
 pub mod message;
+pub mod metrics;
+pub mod notify;
+pub mod protocol;
 pub mod node;
-
+pub mod state;
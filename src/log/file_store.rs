@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::log::{Range, Scan, Store, serialize, deserialize};
+
+/// 每条log记录的头: 4字节大端长度 + 4字节crc32
+const HEADER_LEN: u64 = 8;
+
+/// 提交位置和元数据, 整个文件一起替换
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Meta {
+    committed: u64,
+    metadata: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+/// 文件存储: dir/log 只追加的log记录, dir/meta 提交位置和元数据.
+/// 写入先进操作系统缓存, flush时fsync(fsync为false时只写元数据, 不fsync); 重启时丢掉没写完整的尾部记录
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    file: File,
+    //第i条log(index i+1)记录在文件里的起始位置
+    offsets: Vec<u64>,
+    end: u64,
+    meta: Meta,
+    log_dirty: bool,
+    meta_dirty: bool,
+    fsync: bool,
+}
+
+impl FileStore {
+    pub fn open(dir: impl AsRef<Path>, fsync: bool) -> Result<FileStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let meta = match fs::read(dir.join("meta")) {
+            Ok(bytes) => deserialize(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Meta::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(dir.join("log"))?;
+        let (offsets, end) = FileStore::recover(&mut file)?;
+        let store = FileStore { dir, file, offsets, end, meta, log_dirty: false, meta_dirty: false, fsync };
+        if store.meta.committed > store.size() {
+            return Err(anyhow::anyhow!("committed index {} beyond stored log {}", store.meta.committed, store.size()));
+        }
+        if store.file.metadata()?.len() > end {
+            log::warn!("discarding torn log tail in {:?} after offset {}", store.dir, end);
+            store.file.set_len(end)?;
+            store.file.sync_data()?;
+        }
+        Ok(store)
+    }
+
+    /// 扫描log文件, 返回每条完整记录的起始位置和最后一条完整记录的结尾
+    fn recover(file: &mut File) -> Result<(Vec<u64>, u64)> {
+        let mut bytes = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        let mut offsets = vec![];
+        let mut pos = 0usize;
+        while pos + HEADER_LEN as usize <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into()?) as usize;
+            let crc = u32::from_be_bytes(bytes[pos + 4..pos + 8].try_into()?);
+            let start = pos + HEADER_LEN as usize;
+            if start + len > bytes.len() || crc32fast::hash(&bytes[start..start + len]) != crc {
+                break;
+            }
+            offsets.push(pos as u64);
+            pos = start + len;
+        }
+        Ok((offsets, pos as u64))
+    }
+
+    fn read_at(&self, index: u64) -> Result<Vec<u8>> {
+        let start = self.offsets[index as usize - 1] + HEADER_LEN;
+        let end = self.offsets.get(index as usize).copied().unwrap_or(self.end);
+        let mut buffer = vec![0; (end - start) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// 先写临时文件再改名, 替换是原子的
+    fn write_meta(&mut self) -> Result<()> {
+        let tmp = self.dir.join("meta.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serialize(&self.meta)?)?;
+        if self.fsync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, self.dir.join("meta"))?;
+        if self.fsync {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.meta.metadata.insert(key, value);
+        self.meta_dirty = true;
+        Ok(())
+    }
+
+    fn get_metadata(&self, key: Vec<u8>) -> Result<Vec<u8>> {
+        self.meta.metadata.get(&key).cloned().ok_or_else(|| anyhow::anyhow!("no key"))
+    }
+
+    fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        if index == 0 || index > self.size() {
+            return Ok(None);
+        }
+        self.read_at(index).map(Some)
+    }
+
+    fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + entry.len());
+        record.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        record.extend_from_slice(&crc32fast::hash(&entry).to_be_bytes());
+        record.extend_from_slice(&entry);
+        self.file.write_all(&record)?;
+        self.offsets.push(self.end);
+        self.end += record.len() as u64;
+        self.log_dirty = true;
+        Ok(self.size())
+    }
+
+    fn scan(&self, range: Range) -> Scan<'_> {
+        let start = match range.start {
+            Bound::Included(n) => n.max(1),
+            Bound::Excluded(n) => n + 1,
+            Bound::Unbounded => 1,
+        };
+        let end = match range.end {
+            Bound::Included(n) => n.min(self.size()),
+            Bound::Excluded(n) => n.saturating_sub(1).min(self.size()),
+            Bound::Unbounded => self.size(),
+        };
+        Box::new((start..=end).map(move |index| self.read_at(index)))
+    }
+
+    fn commit(&mut self, index: u64) -> Result<()> {
+        if index > self.size() || index < self.meta.committed {
+            return Err(anyhow::anyhow!(
+            format!("commit failure index:{}, commited:{}", index, self.meta.committed))
+            );
+        }
+        self.meta.committed = index;
+        self.meta_dirty = true;
+        Ok(())
+    }
+
+    fn committed(&self) -> Result<u64> {
+        Ok(self.meta.committed)
+    }
+
+    fn size(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<u64> {
+        if index < self.meta.committed {
+            return Err(anyhow::anyhow!(
+            format!("truncate failure index:{}, commited:{}", index, self.meta.committed))
+            );
+        }
+        if index < self.size() {
+            self.end = self.offsets[index as usize];
+            self.offsets.truncate(index as usize);
+            self.file.set_len(self.end)?;
+            self.log_dirty = true;
+        }
+        Ok(self.size())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.log_dirty {
+            if self.fsync {
+                self.file.sync_data()?;
+            }
+            self.log_dirty = false;
+        }
+        if self.meta_dirty {
+            self.write_meta()?;
+            self.meta_dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 每个测试用自己的空目录
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iraft-file-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// 写n条log, 提交到committed后关闭
+    fn write(dir: &Path, n: u8, committed: u64) {
+        let mut store = FileStore::open(dir, true).unwrap();
+        for i in 1..=n {
+            store.append(vec![i; 10]).unwrap();
+        }
+        store.commit(committed).unwrap();
+        store.set_metadata(b"k".to_vec(), b"v".to_vec()).unwrap();
+        store.flush().unwrap();
+    }
+
+    fn log_len(dir: &Path) -> u64 {
+        fs::metadata(dir.join("log")).unwrap().len()
+    }
+
+    #[test]
+    fn reopen_keeps_log_and_meta() {
+        let dir = dir("reopen");
+        write(&dir, 3, 2);
+        let store = FileStore::open(&dir, true).unwrap();
+        assert_eq!(store.size(), 3);
+        assert_eq!(store.get(2).unwrap(), Some(vec![2; 10]));
+        assert_eq!(store.committed().unwrap(), 2);
+        assert_eq!(store.get_metadata(b"k".to_vec()).unwrap(), b"v");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_truncated() {
+        let dir = dir("torn");
+        write(&dir, 3, 3);
+        let len = log_len(&dir);
+        //写了一半的记录: 头说有100字节, 只写了5字节
+        let mut file = OpenOptions::new().append(true).open(dir.join("log")).unwrap();
+        file.write_all(&100u32.to_be_bytes()).unwrap();
+        file.write_all(&[0; 9]).unwrap();
+        drop(file);
+
+        let mut store = FileStore::open(&dir, true).unwrap();
+        assert_eq!(store.size(), 3);
+        assert_eq!(log_len(&dir), len);
+        //截掉之后接着追加, 重启后还在
+        store.append(vec![4; 10]).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let store = FileStore::open(&dir, true).unwrap();
+        assert_eq!(store.size(), 4);
+        assert_eq!(store.get(4).unwrap(), Some(vec![4; 10]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crc_mismatch_discards_rest() {
+        let dir = dir("crc");
+        write(&dir, 3, 1);
+        //改掉第2条记录里的一个字节, 它和之后的记录都不要了
+        let mut bytes = fs::read(dir.join("log")).unwrap();
+        let record = HEADER_LEN as usize + 10;
+        bytes[record + HEADER_LEN as usize] ^= 0xff;
+        fs::write(dir.join("log"), &bytes).unwrap();
+
+        let store = FileStore::open(&dir, true).unwrap();
+        assert_eq!(store.size(), 1);
+        assert_eq!(log_len(&dir), record as u64);
+        assert_eq!(store.get(1).unwrap(), Some(vec![1; 10]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_committed_log_fails() {
+        let dir = dir("committed");
+        write(&dir, 3, 3);
+        let mut bytes = fs::read(dir.join("log")).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(dir.join("log"), &bytes).unwrap();
+        //已提交的log坏了不能悄悄截掉
+        assert!(FileStore::open(&dir, true).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn meta_replaced_atomically() {
+        let dir = dir("meta");
+        write(&dir, 3, 2);
+        assert!(!dir.join("meta.tmp").exists());
+        //改名之前崩溃: 留下写了一半的临时文件, 用的还是之前的meta
+        fs::write(dir.join("meta.tmp"), b"garbage").unwrap();
+        let mut store = FileStore::open(&dir, true).unwrap();
+        assert_eq!(store.committed().unwrap(), 2);
+        //下次写meta覆盖掉临时文件
+        store.commit(3).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        assert!(!dir.join("meta.tmp").exists());
+        assert_eq!(FileStore::open(&dir, true).unwrap().committed().unwrap(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
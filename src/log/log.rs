@@ -1,9 +1,11 @@
 use anyhow::Result;
-use std::path::Iter;
-use serde::{Deserialize, Serialize};
 use serde_derive::{Deserialize, Serialize};
-use std::ops::{Bound, RangeBounds};
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::sync::Arc;
 use crate::log::{Store, serialize, deserialize, Range};
+use crate::metrics::Metrics;
+use crate::node::ready::HardState;
 
 
 #[derive(Clone, Debug, PartialEq)]
@@ -15,81 +17,270 @@ impl MetadateKey {
     }
 }
 
+/// 集群成员(peer id -> 地址)在元数据里的key
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeersKey;
+
+impl PeersKey {
+    fn encode(&self) -> Vec<u8> {
+        vec![0x01]
+    }
+}
+
 ///一条log的结构
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Entry {
     pub index: u64,
     pub term: u64,
-    pub command: Option<Vec<u8>>,
+    pub command: Command,
 }
 
-///定义面向业务的log操作
-#[derive(Debug)]
+impl Entry {
+    /// 大致字节数, 只算状态机命令, 用于按字节限制写入和复制
+    pub fn size(&self) -> usize {
+        match &self.command {
+            Command::State(command) | Command::SessionState { command, .. } => command.len(),
+            _ => 0,
+        }
+    }
+}
+
+///log的内容
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// 空log, leader当选后追加一条来提交之前任期的log
+    Noop,
+    /// 应用到状态机的命令
+    State(Vec<u8>),
+    /// 成员变更, 提交后生效, 同时只能有一个未提交的变更
+    Membership(ConfigChange),
+    /// 注册客户端会话, 会话id就是这条log的index. time是leader写入时的时间(毫秒), 会话按它过期
+    RegisterSession { time: u64 },
+    /// 带会话的状态机命令, 同一会话的同一个seq只应用一次
+    SessionState { client_id: u64, seq: u64, time: u64, command: Vec<u8> },
+}
+
+///成员变更, 一次只增删一个节点
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConfigChange {
+    AddPeer { id: String, addr: String },
+    RemovePeer { id: String },
+}
+
+///定义面向业务的log操作, 只改内存. 要持久化的部分由节点通过Ready交给调用方, 见LogStore
+#[derive(Debug, Default)]
 pub struct Log {
-    pub(crate) store: Box<dyn Store>,
+    entries: Vec<Entry>,
     pub(crate) last_index: u64,
     pub(crate) last_term: u64,
 
     pub(crate) commit_index: u64,
     pub(crate) commit_term: u64,
-}
 
-pub type Scan<'a> = Box<Iterator<Item=Result<Entry>> + 'a>;
+    //已经交给调用方持久化的最后index和commit index, 之后的变化下次ready时交出去
+    pub(crate) unstable_index: u64,
+    pub(crate) unstable_commit: u64,
+    //这个index之后的log被删除了, 存储上也要删除
+    pub(crate) truncated: Option<u64>,
+    //调用方确认已经持久化的最后index
+    pub(crate) stable_index: u64,
+}
 
 impl Log {
-    pub fn new(store: Box<Store>) -> Log {
-        Log {
-            store,
-            last_term: 0,
-            last_index: 0,
-            commit_index: 0,
-            commit_term: 0,
+    /// 用存储里读出来的log恢复, 都已经持久化
+    pub fn new(entries: Vec<Entry>, commit_index: u64) -> Result<Log> {
+        let mut log = Log { entries, ..Log::default() };
+        if let Some(entry) = log.entries.last() {
+            log.last_index = entry.index;
+            log.last_term = entry.term;
         }
+        if commit_index > 0 {
+            log.commit(commit_index)?;
+        }
+        log.unstable_index = log.last_index;
+        log.unstable_commit = log.commit_index;
+        log.stable_index = log.last_index;
+        Ok(log)
     }
 
-    ///元数据的get / set
-    pub fn save_metadata(&mut self, term: u64, voted_for: Option<&str>) -> Result<()> {
-        self.store.set_metadata(MetadateKey.encode(), serialize(&(term, voted_for))?)
-    }
-
-    pub fn get_metadata(&self) -> Result<(u64, Option<String>)> {
-        let value = self.store.get_metadata(MetadateKey.encode())?;
-        Ok(deserialize(&value[..])?)
+    /// 第一条log的index, 没有log时为0
+    pub fn first_index(&self) -> u64 {
+        if self.last_index == 0 { 0 } else { 1 }
     }
 
     ///数据的 get / set
-    pub fn get(&self, index: u64) -> Result<Option<Entry>> {
-        self.store.get(index)?.map(|v| deserialize(&v)).transpose()
+    pub fn get(&self, index: u64) -> Option<&Entry> {
+        if index == 0 {
+            return None;
+        }
+        self.entries.get(index as usize - 1)
     }
-    pub fn append(&mut self, term: u64, command: Option<Vec<u8>>) -> Result<Entry> {
+    pub fn append(&mut self, term: u64, command: Command) -> Entry {
         let entry = Entry { index: self.last_index + 1, term, command };
-        let _ = self.store.append(serialize(&entry)?)?;
+        self.entries.push(entry.clone());
         self.last_index = entry.index;
         self.last_term = term;
-        Ok(entry)
+        entry
     }
-    pub fn scan(&self, range: impl RangeBounds<u64>) -> Scan {
-        Box::new(self.store.scan(Range::from(range)).map(|r| r.and_then(|v| deserialize(&v))))
+    pub fn scan(&self, range: impl RangeBounds<u64>) -> impl Iterator<Item=&Entry> {
+        self.entries.iter().filter(move |e| range.contains(&e.index))
+    }
+
+    /// 接收leader复制过来的log: 跳过已有的, 删掉冲突的, 追加缺少的
+    pub fn splice(&mut self, entries: Vec<Entry>) -> Result<u64> {
+        for entry in entries {
+            if let Some(existing) = self.get(entry.index) {
+                if existing.term == entry.term {
+                    continue;
+                }
+                self.truncate(entry.index - 1)?;
+            }
+            if entry.index != self.last_index + 1 {
+                return Err(anyhow::anyhow!("splice index:{} after last_index:{}", entry.index, self.last_index));
+            }
+            self.append(entry.term, entry.command);
+        }
+        Ok(self.last_index)
+    }
+
+    /// 删除index之后的log, 已提交的log不可删除
+    pub fn truncate(&mut self, index: u64) -> Result<u64> {
+        if index < self.commit_index {
+            return Err(anyhow::anyhow!("truncate index:{} before commit_index:{}", index, self.commit_index));
+        }
+        let (last_index, last_term) = match self.get(index) {
+            Some(entry) => (entry.index, entry.term),
+            None if index == 0 => (0, 0),
+            None => return Err(anyhow::anyhow!("truncate index:{} not found", index)),
+        };
+        self.entries.truncate(index as usize);
+        self.last_index = last_index;
+        self.last_term = last_term;
+        //只有已经交出去的log才需要从存储上删
+        if index < self.unstable_index {
+            self.unstable_index = index;
+            self.truncated = Some(self.truncated.map_or(index, |t| t.min(index)));
+        }
+        self.stable_index = self.stable_index.min(index);
+        Ok(index)
     }
 
     /// 提交日志
     pub fn commit(&mut self, index: u64) -> Result<u64> {
-        match self.get(index)? {
-            Some(entry) => {
-                self.store.commit(index)?;
-                self.commit_index = entry.index;
-                self.commit_term = entry.term;
+        match self.get(index).map(|e| e.term) {
+            Some(term) => {
+                self.commit_index = index;
+                self.commit_term = term;
                 Ok(index)
             }
             None => Err(anyhow::anyhow!(format!("提交index:{}错误", index))),
         }
     }
 
-    pub fn has(&self, index: u64, term: u64) -> Result<bool> {
-        match self.get(index)? {
-            Some(e) => Ok(e.term == term),
-            None if index == 0 && term == 0 => Ok(true),
-            None => Ok(false),
+    pub fn has(&self, index: u64, term: u64) -> bool {
+        match self.get(index) {
+            Some(e) => e.term == term,
+            None => index == 0 && term == 0,
+        }
+    }
+
+    /// 取出还没交给调用方持久化的log
+    pub(crate) fn take_unstable(&mut self) -> Vec<Entry> {
+        let entries = self.scan(self.unstable_index + 1..).cloned().collect();
+        self.unstable_index = self.last_index;
+        entries
+    }
+
+    /// 调用方确认index之前的log都已持久化
+    pub(crate) fn stable_to(&mut self, index: u64) {
+        self.stable_index = index.min(self.last_index);
+    }
+}
+
+/// 一次要持久化的内容, 从Ready里取出, 按字段顺序写入
+#[derive(Debug, Default)]
+pub struct LogWrite {
+    /// 删除这个index之后(不含)的log
+    pub truncate: Option<u64>,
+    pub entries: Vec<Entry>,
+    pub hard_state: Option<HardState>,
+    pub peers: Option<Peers>,
+    pub commit_index: Option<u64>,
+}
+
+impl LogWrite {
+    pub fn is_empty(&self) -> bool {
+        self.truncate.is_none()
+            && self.entries.is_empty()
+            && self.hard_state.is_none()
+            && self.peers.is_none()
+            && self.commit_index.is_none()
+    }
+}
+
+/// 集群成员, peer id -> 地址
+pub type Peers = HashMap<String, String>;
+
+/// 把Ready里要持久化的内容写到Store, 重启时从Store读出来恢复节点. 在节点之外由调用方使用
+#[derive(Debug)]
+pub struct LogStore {
+    store: Box<dyn Store>,
+    metrics: Arc<Metrics>,
+}
+
+impl LogStore {
+    pub fn new(store: Box<dyn Store>, metrics: Arc<Metrics>) -> LogStore {
+        LogStore { store, metrics }
+    }
+
+    /// 读出log, 任期和投票, 以及成员变更后保存的成员(没变更过为None)
+    pub fn load(&self) -> Result<(Log, HardState, Option<Peers>)> {
+        let entries = self.store.scan(Range::from(..))
+            .map(|r| r.and_then(|v| deserialize(&v)))
+            .collect::<Result<Vec<Entry>>>()?;
+        let log = Log::new(entries, self.store.committed()?)?;
+        let hard_state = match self.store.get_metadata(MetadateKey.encode()) {
+            Ok(value) => deserialize(&value[..])?,
+            Err(_) => HardState::default(),
+        };
+        let peers = match self.store.get_metadata(PeersKey.encode()) {
+            Ok(value) => Some(deserialize(&value[..])?),
+            Err(_) => None,
+        };
+        Ok((log, hard_state, peers))
+    }
+
+    /// 按顺序执行删除, 追加, 保存元数据, 提交, 返回写入log的字节数. 之后要flush才算持久化
+    pub fn write(&mut self, write: &LogWrite) -> Result<usize> {
+        let mut written = 0;
+        if let Some(index) = write.truncate {
+            self.store.truncate(index)?;
         }
+        for entry in write.entries.iter() {
+            if entry.index != self.store.size() + 1 {
+                return Err(anyhow::anyhow!("append index:{} after stored:{}", entry.index, self.store.size()));
+            }
+            let bytes = serialize(entry)?;
+            written += bytes.len();
+            let store = &mut self.store;
+            self.metrics.store_append_seconds.time(|| store.append(bytes))?;
+        }
+        if let Some(hard_state) = &write.hard_state {
+            self.store.set_metadata(MetadateKey.encode(), serialize(hard_state)?)?;
+        }
+        //成员变更生效后保存, 重启时以此为准
+        if let Some(peers) = &write.peers {
+            self.store.set_metadata(PeersKey.encode(), serialize(peers)?)?;
+        }
+        if let Some(index) = write.commit_index {
+            self.store.commit(index)?;
+        }
+        Ok(written)
+    }
+
+    /// 把store缓冲的写入刷到持久化介质
+    pub fn flush(&mut self) -> Result<()> {
+        let store = &mut self.store;
+        self.metrics.store_fsync_seconds.time(|| store.flush())
     }
 }
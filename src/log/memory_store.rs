@@ -16,22 +16,27 @@ impl MemoryStore {
     }
 }
 
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
 impl Store for MemoryStore {
     fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.metadata.insert(key, value).unwrap();
+        self.metadata.insert(key, value);
         Ok(())
     }
 
     fn get_metadata(&self, key: Vec<u8>) -> Result<Vec<u8>> {
-        self.metadata.get(&key).map(|v|v.to_vec()).ok_or(anyhow::anyhow!("no key"))
+        self.metadata.get(&key).map(|v|v.to_vec()).ok_or_else(|| anyhow::anyhow!("no key"))
     }
 
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
         if index == 0 {
             Ok(None)
         } else {
-            let v = self.log.get(index as usize - 1).unwrap();
-            Ok(Some(v.clone()))
+            Ok(self.log.get(index as usize - 1).cloned())
         }
     }
 
@@ -40,7 +45,7 @@ impl Store for MemoryStore {
         Ok(self.log.len() as u64)
     }
 
-    fn scan(&self, range: Range) -> Scan {
+    fn scan(&self, range: Range) -> Scan<'_> {
         Box::new(
             self.log
                 .iter()
@@ -48,7 +53,7 @@ impl Store for MemoryStore {
                     Bound::Included(n) => n as usize,
                     Bound::Excluded(0) => 0,
                     Bound::Excluded(n) => n as usize - 1,
-                    Bound::Unbounded => std::usize::MAX,
+                    Bound::Unbounded => usize::MAX,
                 })
                 .skip(match range.start {
                     Bound::Included(0) => 0,
@@ -62,17 +67,34 @@ impl Store for MemoryStore {
     }
 
     fn commit(&mut self, index: u64) -> Result<()> {
-        if index == self.committed {
-            self.committed = index;
-        } else {
+        if index > self.log.len() as u64 || index < self.committed {
             return Err(anyhow::anyhow!(
             format!("commit failure index:{}, commited:{}", index, self.committed))
             );
         }
+        self.committed = index;
         Ok(())
     }
 
     fn committed(&self) -> Result<u64> {
         Ok(self.committed)
     }
-}
\ No newline at end of file
+
+    fn size(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<u64> {
+        if index < self.committed {
+            return Err(anyhow::anyhow!(
+            format!("truncate failure index:{}, commited:{}", index, self.committed))
+            );
+        }
+        self.log.truncate(index as usize);
+        Ok(self.log.len() as u64)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
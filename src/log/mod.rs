@@ -1,5 +1,8 @@
-pub mod memory_store;
+#[allow(clippy::module_inception)]
 pub mod log;
+pub mod memory_store;
+pub mod file_store;
+pub mod worker;
 
 
 use std::fmt::Debug;
@@ -41,12 +44,16 @@ pub trait Store: Debug + Sync + Send {
     //log数据操作
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>>;
     fn append(&mut self, entry: Vec<u8>) -> Result<u64>;
-    fn scan(&self, range: Range) -> Scan;
+    fn scan(&self, range: Range) -> Scan<'_>;
     //提交log和获取已提交的log index
     fn commit(&mut self, index: u64) -> Result<()>;
     fn committed(&self) -> Result<u64>;
-    //fn size(&self) -> u64;
-    //fn truncate(&mut self, index: u64) -> Result<u64>;
+    //log条数
+    fn size(&self) -> u64;
+    //删除index之后的log(不含index), 返回新的最后index; 已提交的log不可删除
+    fn truncate(&mut self, index: u64) -> Result<u64>;
+    //把缓冲的写入刷到持久化介质
+    fn flush(&mut self) -> Result<()>;
 }
 
 
@@ -0,0 +1,237 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::channel::mpsc::{self as async_mpsc, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+
+use crate::conf::{SyncConfig, SyncPolicy};
+use crate::log::log::{Entry, LogStore, LogWrite};
+use crate::metrics::Metrics;
+
+enum Job {
+    Write(LogWrite),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// 在单独的线程上执行存储写入, 慢的fsync不会卡住event loop. 写入按提交的顺序执行,
+/// 每完成一个就在done通道上通知一次结果. 按SyncConfig把一批写入合并成一次flush(组提交)
+#[derive(Debug)]
+pub struct StoreWorker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl StoreWorker {
+    /// 启动写入线程, 返回提交写入的一端和完成通知
+    pub fn spawn(
+        id: &str,
+        store: LogStore,
+        sync: SyncConfig,
+        metrics: Arc<Metrics>,
+    ) -> Result<(StoreWorker, UnboundedReceiver<Result<()>>)> {
+        let (jobs, jobs_rx) = mpsc::channel();
+        let (done_tx, done_rx) = async_mpsc::unbounded();
+        let mut worker = Worker { store, jobs: jobs_rx, done: done_tx, sync, metrics };
+        thread::Builder::new().name(format!("iraft-store-{}", id)).spawn(move || worker.run())?;
+        Ok((StoreWorker { jobs }, done_rx))
+    }
+
+    /// 提交一次写入, 不等待完成
+    pub fn write(&self, write: LogWrite) -> Result<()> {
+        self.jobs.send(Job::Write(write)).map_err(|_| anyhow::anyhow!("store worker stopped"))
+    }
+
+    /// 等之前提交的写入都完成后刷盘
+    pub async fn flush(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.jobs.send(Job::Flush(tx)).map_err(|_| anyhow::anyhow!("store worker stopped"))?;
+        rx.await?
+    }
+}
+
+struct Worker {
+    store: LogStore,
+    jobs: mpsc::Receiver<Job>,
+    done: UnboundedSender<Result<()>>,
+    sync: SyncConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl Worker {
+    /// StoreWorker丢掉后线程结束
+    fn run(&mut self) {
+        while let Ok(job) = self.jobs.recv() {
+            let mut writes = vec![];
+            let mut flushes = vec![];
+            match job {
+                Job::Write(write) => writes.push(write),
+                Job::Flush(tx) => flushes.push(tx),
+            }
+            if !writes.is_empty() {
+                self.collect(&mut writes, &mut flushes);
+            }
+            if self.commit(writes, flushes).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// 在时间窗口内继续收写入, 直到数量或大小到上限
+    fn collect(&self, writes: &mut Vec<LogWrite>, flushes: &mut Vec<oneshot::Sender<Result<()>>>) {
+        if self.sync.policy != SyncPolicy::Batch {
+            return;
+        }
+        let deadline = Instant::now() + Duration::from_millis(self.sync.max_delay_ms);
+        let mut bytes = writes.iter().map(size).sum::<usize>();
+        while writes.len() < self.sync.max_writes && bytes < self.sync.max_bytes {
+            let job = match self.jobs.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match job {
+                Job::Write(write) => {
+                    bytes += size(&write);
+                    writes.push(write);
+                }
+                //要等这批写完, 也就不用再等更多写入了
+                Job::Flush(tx) => {
+                    flushes.push(tx);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// 执行一批写入, 一次flush, 然后逐个通知. 通知发不出去(event loop已退出)时返回Err
+    fn commit(&mut self, writes: Vec<LogWrite>, flushes: Vec<oneshot::Sender<Result<()>>>) -> Result<(), ()> {
+        let n = writes.len();
+        let mut written = 0;
+        let mut result = Ok(());
+        for write in writes.iter() {
+            match self.store.write(write) {
+                Ok(bytes) => written += bytes,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            result = self.store.flush();
+        }
+        if n > 0 {
+            self.metrics.store_batch_writes.record(n as f64);
+            self.metrics.store_batch_bytes.record(written as f64);
+        }
+        //出错时这一批都算失败
+        let outcome = || result.as_ref().map(|_| ()).map_err(|e| anyhow::anyhow!("{:#}", e));
+        for _ in 0..n {
+            self.done.unbounded_send(outcome()).map_err(|_| ())?;
+        }
+        for tx in flushes {
+            let _ = tx.send(outcome());
+        }
+        Ok(())
+    }
+}
+
+/// 一次写入里log的大致字节数
+fn size(write: &LogWrite) -> usize {
+    write.entries.iter().map(Entry::size).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::log::Command;
+    use crate::log::memory_store::MemoryStore;
+
+    fn worker(sync: SyncConfig) -> (Worker, mpsc::Sender<Job>, UnboundedReceiver<Result<()>>) {
+        let metrics = Arc::new(Metrics::new());
+        let store = LogStore::new(Box::new(MemoryStore::new()), metrics.clone());
+        let (jobs, jobs_rx) = mpsc::channel();
+        let (done_tx, done_rx) = async_mpsc::unbounded();
+        (Worker { store, jobs: jobs_rx, done: done_tx, sync, metrics }, jobs, done_rx)
+    }
+
+    fn write(index: u64, bytes: usize) -> LogWrite {
+        let entry = Entry { index, term: 1, command: Command::State(vec![0; bytes]) };
+        LogWrite { entries: vec![entry], commit_index: Some(index), ..LogWrite::default() }
+    }
+
+    fn batch(max_delay_ms: u64, max_writes: usize, max_bytes: usize) -> SyncConfig {
+        SyncConfig { policy: SyncPolicy::Batch, max_delay_ms, max_writes, max_bytes }
+    }
+
+    #[test]
+    fn batch_takes_queued_writes_up_to_limit() {
+        let (worker, jobs, _done) = worker(batch(0, 3, 1 << 20));
+        for i in 2..=5 {
+            jobs.send(Job::Write(write(i, 1))).unwrap();
+        }
+        let (mut writes, mut flushes) = (vec![write(1, 1)], vec![]);
+        worker.collect(&mut writes, &mut flushes);
+        assert_eq!(writes.len(), 3);
+        //超出上限的留给下一批
+        assert_eq!(worker.jobs.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn batch_stops_at_byte_limit() {
+        let (worker, jobs, _done) = worker(batch(0, 100, 1000));
+        jobs.send(Job::Write(write(2, 600))).unwrap();
+        jobs.send(Job::Write(write(3, 1))).unwrap();
+        let (mut writes, mut flushes) = (vec![write(1, 600)], vec![]);
+        worker.collect(&mut writes, &mut flushes);
+        assert_eq!(writes.len(), 2);
+    }
+
+    #[test]
+    fn batch_waits_for_writes_within_delay() {
+        let (worker, jobs, _done) = worker(batch(500, 100, 1 << 20));
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            jobs.send(Job::Write(write(2, 1))).unwrap();
+            let (tx, _rx) = oneshot::channel();
+            //flush之后的写入不进这一批
+            jobs.send(Job::Flush(tx)).unwrap();
+            jobs.send(Job::Write(write(3, 1))).unwrap();
+        });
+        let (mut writes, mut flushes) = (vec![write(1, 1)], vec![]);
+        let start = Instant::now();
+        worker.collect(&mut writes, &mut flushes);
+        sender.join().unwrap();
+        assert_eq!((writes.len(), flushes.len()), (2, 1));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn every_write_flushed_alone() {
+        let (worker, jobs, _done) = worker(SyncConfig { policy: SyncPolicy::Every, ..batch(100, 100, 1 << 20) });
+        jobs.send(Job::Write(write(2, 1))).unwrap();
+        let (mut writes, mut flushes) = (vec![write(1, 1)], vec![]);
+        worker.collect(&mut writes, &mut flushes);
+        assert_eq!(writes.len(), 1);
+    }
+
+    #[test]
+    fn group_commit_notifies_each_write() {
+        let (mut worker, jobs, mut done) = worker(batch(0, 100, 1 << 20));
+        for i in 1..=3 {
+            jobs.send(Job::Write(write(i, 1))).unwrap();
+        }
+        let (tx, flushed) = oneshot::channel();
+        jobs.send(Job::Flush(tx)).unwrap();
+        drop(jobs);
+        worker.run();
+
+        for _ in 1..=3 {
+            assert!(done.try_next().unwrap().unwrap().is_ok());
+        }
+        assert!(crate::runtime::block_on(flushed).unwrap().is_ok());
+        let (log, _, _) = worker.store.load().unwrap();
+        assert_eq!((log.last_index, log.commit_index), (3, 3));
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::LevelFilter;
+use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode, WriteLogger};
+
+use crate::conf::Config;
+
+/// 按配置初始化全局日志:
+/// log_file 为空时输出到stderr, 否则追加写入 data_dir/log_file
+pub fn init(conf: &Config) -> Result<()> {
+    let level = LevelFilter::from_str(&conf.log_level)
+        .map_err(|_| anyhow::anyhow!("invalid log_level: {}", conf.log_level))?;
+    let config = ConfigBuilder::new()
+        .set_target_level(LevelFilter::Error)
+        .set_thread_level(LevelFilter::Off)
+        .set_time_format_str("%Y-%m-%d %H:%M:%S%.3f")
+        .set_time_to_local(true)
+        .build();
+
+    match &conf.log_file {
+        Some(file) => {
+            fs::create_dir_all(&conf.data_dir)?;
+            let path = Path::new(&conf.data_dir).join(file);
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            WriteLogger::init(level, config, file)?;
+        }
+        None => TermLogger::init(level, config, TerminalMode::Stderr, ColorChoice::Auto)?,
+    }
+    Ok(())
+}
+
+/// 网络任务日志行的前缀: 节点id, 当前任期和角色. event loop每轮更新, 任务记日志时读最新的
+#[derive(Debug)]
+pub(crate) struct LogContext {
+    id: String,
+    //任期和角色一起更新, 不会读到一半
+    state: Mutex<(u64, &'static str)>,
+}
+
+impl LogContext {
+    pub(crate) fn new(id: &str, term: u64, role: &'static str) -> LogContext {
+        LogContext { id: id.to_string(), state: Mutex::new((term, role)) }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub(crate) fn update(&self, term: u64, role: &'static str) {
+        *self.state.lock().unwrap() = (term, role);
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (term, role) = *self.state.lock().unwrap();
+        write!(f, "node={} term={} role={}", self.id, term, role)
+    }
+}
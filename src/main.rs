@@ -1,29 +1,53 @@
 use iraft::conf::Config;
-use futures::executor::block_on;
 use iraft::server::RaftServer;
-use std::time::Duration;
-use iraft::message::{Message, Event, Address};
+use iraft::state::Echo;
+use futures::StreamExt;
 
-#[async_std::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> anyhow::Result<()> {
+    iraft::runtime::block_on(run())
+}
+
+async fn run() -> anyhow::Result<()> {
     let args = std::env::args().nth(1);
 
+    //iraft status <listen_raft地址> [配置文件]: 查询运行中节点的状态, 用配置文件里的TLS证书
+    if args.as_deref() == Some("status") {
+        let addr = std::env::args().nth(2).ok_or_else(|| anyhow::anyhow!("usage: iraft status <addr> [config]"))?;
+        let tls = match std::env::args().nth(3) {
+            Some(file) => Config::new(&file)?.tls,
+            None => None,
+        };
+        println!("{:#?}", iraft::handle::fetch_status(&addr, tls.as_ref()).await?);
+        return Ok(());
+    }
+
     let cfg = match args {
-        Some(arg) => Config::new(arg.as_str()).unwrap(),
+        Some(arg) => Config::new(arg.as_str())?,
         None => Config::default(),
     };
+    iraft::logging::init(&cfg)?;
 
-    let trs = RaftServer::new(cfg).await;
+    let trs = RaftServer::new(cfg, Box::new(Echo::default())).await?;
 
-
-    let (tx, rx) = futures::channel::mpsc::unbounded();
-    std::thread::spawn(move || {
-        for i in 2..10 {
-            std::thread::sleep(Duration::from_secs(5));
-            let m = Message { term: 11, from:Address::Local, to: Address::Peers, event: Event::None };
-            //tx.unbounded_send(m);
+    //SIGINT/SIGTERM: 转移leader后停止, 再收到一次直接退出
+    let handle = trs.handle();
+    let (signal_tx, mut signal_rx) = futures::channel::mpsc::unbounded();
+    ctrlc::set_handler(move || {
+        if signal_tx.unbounded_send(()).is_err() {
+            std::process::exit(130);
+        }
+    })?;
+    iraft::runtime::spawn(async move {
+        if signal_rx.next().await.is_some() {
+            signal_rx.close();
+            if let Err(e) = handle.shutdown(true).await {
+                log::error!("shutdown failed: {}", e);
+            }
         }
     });
-    trs.serve(rx).await;
+
+    let (_client_tx, client_rx) = futures::channel::mpsc::channel(1);
+    let status = trs.serve(client_rx).await?;
+    log::info!("final status: {:?}", status);
     Ok(())
 }
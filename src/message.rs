@@ -1,5 +1,12 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::log::log::{ConfigChange, Entry};
+use crate::node::Status;
+use crate::transport;
+
 /// A message address.
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Address {
@@ -13,12 +20,89 @@ pub enum Address {
     Client,
 }
 
+impl Address {
+    /// Peer地址的id
+    pub fn peer(&self) -> Option<&str> {
+        match self {
+            Address::Peer(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+
+/// 客户端对leader的请求
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    /// 提交命令, 应用到状态机后回复
+    Propose(Vec<u8>),
+    /// 线性一致读, 在状态机上查询
+    Read(Vec<u8>),
+    /// 把leader转移给指定节点, None时选复制进度最快的
+    TransferLeadership(Option<String>),
+    /// 增删节点
+    ChangeMembership(ConfigChange),
+    /// 注册客户端会话, 返回8字节大端的会话id
+    RegisterSession,
+    /// 带会话提交命令, 同一会话重复的seq不会再应用, 直接返回上次的结果
+    SessionPropose { client_id: u64, seq: u64, command: Vec<u8> },
+}
+
+/// 客户端请求失败的原因
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestError {
+    /// 本节点不是leader, 没有执行. leader是本节点知道的leader id
+    NotLeader { leader: Option<String> },
+    /// 请求太多, 没有执行
+    Overloaded,
+    /// 暂时不能处理(如leader转移中), 没有执行, 可以稍后重试
+    Unavailable(String),
+    /// leader在请求完成前下台, 命令可能已经提交也可能没有
+    LeadershipLost,
+    /// 等到截止时间还没有结果, 命令可能已经提交也可能没有
+    Timeout,
+    /// 请求不合法(如成员变更冲突, 命令过大), 没有执行, 重试也没用
+    Rejected(String),
+    /// 状态机执行命令或查询时返回的错误
+    State(String),
+    /// 节点停止了
+    Stopped,
+    /// 节点内部错误, 如存储失败
+    Internal(String),
+    /// 会话不存在或者已经过期, 没有执行, 要重新注册会话
+    SessionExpired,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::NotLeader { leader: Some(leader) } => write!(f, "not leader, leader is {}", leader),
+            RequestError::NotLeader { leader: None } => write!(f, "not leader"),
+            RequestError::Overloaded => write!(f, "overloaded"),
+            RequestError::Unavailable(reason) => write!(f, "unavailable: {}", reason),
+            RequestError::LeadershipLost => write!(f, "leadership lost"),
+            RequestError::Timeout => write!(f, "timed out"),
+            RequestError::Rejected(reason) => write!(f, "rejected: {}", reason),
+            RequestError::State(reason) => write!(f, "state machine error: {}", reason),
+            RequestError::Stopped => write!(f, "raft server stopped"),
+            RequestError::Internal(reason) => write!(f, "internal error: {}", reason),
+            RequestError::SessionExpired => write!(f, "session expired"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// 客户端请求的结果: 状态机返回值, 或者失败原因
+pub type Response = Result<Vec<u8>, RequestError>;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Event {
+    //read_seq用于确认leader身份, 线性一致读要等多数节点回应了不小于它的read_seq
     Heartbeat {
         commit_index: u64,
         commit_term: u64,
+        read_seq: u64,
     },
     //candidate 请求投票
     SolicitVote {
@@ -31,10 +115,80 @@ pub enum Event {
     ConfirmLeader {
         commit_index: u64,
         has_committed: bool,
+        read_seq: u64,
+    },
+    //leader复制log给follower, base为entries前一条log
+    ReplicateEntries {
+        base_index: u64,
+        base_term: u64,
+        entries: Vec<Entry>,
+    },
+    //follower已经有了last_index及之前的log
+    AcceptEntries {
+        last_index: u64,
+    },
+    //base对不上, leader需要往前找; last_index是follower最后一条log, leader可以直接跳过去
+    RejectEntries {
+        base_index: u64,
+        last_index: u64,
+    },
+    //leader转移: 让follower立即发起选举
+    TimeoutNow,
+    //客户端请求, id由客户端生成, 用于匹配回复
+    ClientRequest {
+        id: Vec<u8>,
+        request: Request,
+    },
+    //命令应用到状态机后的结果, 或者被拒绝的原因
+    ClientResponse {
+        id: Vec<u8>,
+        response: Response,
+    },
+    //成员变更生效, 通知本地transport, 只在本节点内部传递
+    PeersChanged {
+        peers: HashMap<String, String>,
+    },
+    //transport报告到peer的连接断开或恢复, 只在本节点内部传递
+    PeerConnection {
+        peer: String,
+        connected: bool,
+    },
+    //transport丢掉了发往peer的日志复制, leader要从peer的进度重新探测, 只在本节点内部传递
+    ReplicationDropped {
+        peer: String,
+    },
+    //运维查询节点状态
+    StatusRequest,
+    StatusResponse {
+        status: Status,
     },
     None,
 }
 
+impl Event {
+    /// 事件类型名, 用于日志和指标
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Heartbeat { .. } => "heartbeat",
+            Event::SolicitVote { .. } => "solicit_vote",
+            Event::GrantVote => "grant_vote",
+            Event::ConfirmLeader { .. } => "confirm_leader",
+            Event::ReplicateEntries { .. } => "replicate_entries",
+            Event::AcceptEntries { .. } => "accept_entries",
+            Event::RejectEntries { .. } => "reject_entries",
+            Event::TimeoutNow => "timeout_now",
+            Event::ClientRequest { .. } => "client_request",
+            Event::ClientResponse { .. } => "client_response",
+            Event::PeersChanged { .. } => "peers_changed",
+            Event::PeerConnection { .. } => "peer_connection",
+            Event::ReplicationDropped { .. } => "replication_dropped",
+            Event::StatusRequest => "status_request",
+            Event::StatusResponse { .. } => "status_response",
+            Event::None => "none",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
     pub term: u64,
@@ -43,5 +197,18 @@ pub struct Message {
     pub event: Event,
 }
 
+impl Message {
+    /// 编码成网络上一帧的内容(不含长度前缀)
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// 解码网络上收到的一帧. 输入可以是任意字节: 出错时返回错误, 不会panic,
+    /// 也不会按消息里声明的长度分配内存
+    pub fn decode(bytes: &[u8]) -> Result<Message> {
+        transport::decode(bytes)
+    }
+}
+
 
 //pub type Message = Vec<u8>;
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::{AsyncReadExt, AsyncWriteExt};
+
+use crate::runtime::{self, TcpListener, TcpStream};
+
+/// 存储耗时直方图的桶(秒)
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+/// 每次fsync合并的写入数
+const BATCH_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+/// 每次fsync写入的字节数
+const BYTES_BUCKETS: &[f64] = &[256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0];
+/// accept出错后等一会再继续, 文件描述符用完时不要空转
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 单调递增计数器
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 可任意设置的数值
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, v: u64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 带标签的数值, 标签值 -> 数值
+#[derive(Debug, Default)]
+pub struct Family(Mutex<BTreeMap<Vec<String>, u64>>);
+
+impl Family {
+    pub fn set(&self, labels: &[&str], v: u64) {
+        self.0.lock().unwrap().insert(labels.iter().map(|l| l.to_string()).collect(), v);
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self.0.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let key: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+        self.0.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+
+    pub fn remove(&self, labels: &[&str]) {
+        let key: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+        self.0.lock().unwrap().remove(&key);
+    }
+
+    /// 清空所有标签, 用于角色/leader/peer这类会消失的维度
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistogramInner {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+/// 直方图, bounds是各个桶的上界
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    inner: Mutex<HistogramInner>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, inner: Mutex::new(HistogramInner::default()) }
+    }
+
+    pub fn record(&self, value: f64) {
+        let mut h = self.inner.lock().unwrap();
+        if h.buckets.is_empty() {
+            h.buckets = vec![0; self.bounds.len()];
+        }
+        for (i, le) in self.bounds.iter().enumerate() {
+            if value <= *le {
+                h.buckets[i] += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += value;
+    }
+
+    /// 记录耗时(秒)
+    pub fn observe(&self, d: Duration) {
+        self.record(d.as_secs_f64());
+    }
+
+    /// 执行f并记录耗时
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let r = f();
+        self.observe(start.elapsed());
+        r
+    }
+}
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    CounterFamily(Arc<Family>, &'static [&'static str]),
+    GaugeFamily(Arc<Family>, &'static [&'static str]),
+    Histogram(Arc<Histogram>),
+}
+
+/// 指标注册表, 按注册顺序输出Prometheus文本格式
+#[derive(Default)]
+pub struct Registry {
+    metrics: Vec<(&'static str, &'static str, Metric)>,
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.metrics.iter().map(|m| m.0)).finish()
+    }
+}
+
+impl Registry {
+    pub fn counter(&mut self, name: &'static str, help: &'static str) -> Arc<Counter> {
+        let m = Arc::new(Counter::default());
+        self.metrics.push((name, help, Metric::Counter(m.clone())));
+        m
+    }
+
+    pub fn gauge(&mut self, name: &'static str, help: &'static str) -> Arc<Gauge> {
+        let m = Arc::new(Gauge::default());
+        self.metrics.push((name, help, Metric::Gauge(m.clone())));
+        m
+    }
+
+    pub fn counter_family(&mut self, name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Arc<Family> {
+        let m = Arc::new(Family::default());
+        self.metrics.push((name, help, Metric::CounterFamily(m.clone(), labels)));
+        m
+    }
+
+    pub fn gauge_family(&mut self, name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Arc<Family> {
+        let m = Arc::new(Family::default());
+        self.metrics.push((name, help, Metric::GaugeFamily(m.clone(), labels)));
+        m
+    }
+
+    pub fn histogram(&mut self, name: &'static str, help: &'static str, bounds: &'static [f64]) -> Arc<Histogram> {
+        let m = Arc::new(Histogram::new(bounds));
+        self.metrics.push((name, help, Metric::Histogram(m.clone())));
+        m
+    }
+
+    /// Prometheus text exposition format 0.0.4
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, metric) in &self.metrics {
+            let kind = match metric {
+                Metric::Counter(_) | Metric::CounterFamily(..) => "counter",
+                Metric::Gauge(_) | Metric::GaugeFamily(..) => "gauge",
+                Metric::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            match metric {
+                Metric::Counter(c) => { let _ = writeln!(out, "{} {}", name, c.get()); }
+                Metric::Gauge(g) => { let _ = writeln!(out, "{} {}", name, g.get()); }
+                Metric::CounterFamily(f, labels) | Metric::GaugeFamily(f, labels) => {
+                    for (values, v) in f.0.lock().unwrap().iter() {
+                        let _ = writeln!(out, "{}{{{}}} {}", name, render_labels(labels, values), v);
+                    }
+                }
+                Metric::Histogram(h) => {
+                    let bounds = h.bounds;
+                    let h = h.inner.lock().unwrap();
+                    for (i, le) in bounds.iter().enumerate() {
+                        let n = h.buckets.get(i).copied().unwrap_or(0);
+                        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, n);
+                    }
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, h.count);
+                    let _ = writeln!(out, "{}_sum {}", name, h.sum);
+                    let _ = writeln!(out, "{}_count {}", name, h.count);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(names: &[&str], values: &[String]) -> String {
+    names.iter().zip(values)
+        .map(|(n, v)| format!("{}=\"{}\"", n, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 一个raft节点的全部指标
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    pub term: Arc<Gauge>,
+    pub role: Arc<Family>,
+    pub leader: Arc<Family>,
+    pub commit_index: Arc<Gauge>,
+    pub applied_index: Arc<Gauge>,
+    pub last_index: Arc<Gauge>,
+    pub peer_match_index: Arc<Family>,
+    pub peer_lag: Arc<Family>,
+    pub peer_connected: Arc<Family>,
+
+    pub elections: Arc<Counter>,
+    pub proposals_accepted: Arc<Counter>,
+    pub proposals_rejected: Arc<Counter>,
+    pub requests_forwarded: Arc<Counter>,
+    pub messages: Arc<Family>,
+    pub invalid_messages: Arc<Counter>,
+    pub handshake_failures: Arc<Counter>,
+    pub bad_frames: Arc<Counter>,
+    pub task_panics: Arc<Counter>,
+    pub accept_errors: Arc<Family>,
+
+    pub inbound_queue: Arc<Gauge>,
+    pub outbound_queue: Arc<Gauge>,
+    pub request_queue: Arc<Gauge>,
+    pub peer_queue: Arc<Family>,
+    pub peer_write_batch: Arc<Histogram>,
+    pub requests_overloaded: Arc<Counter>,
+    pub request_batch_size: Arc<Histogram>,
+    pub client_connections: Arc<Gauge>,
+    pub sessions: Arc<Gauge>,
+
+    pub store_append_seconds: Arc<Histogram>,
+    pub store_fsync_seconds: Arc<Histogram>,
+    pub store_batch_writes: Arc<Histogram>,
+    pub store_batch_bytes: Arc<Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let mut r = Registry::default();
+        Metrics {
+            term: r.gauge("iraft_term", "Current term"),
+            role: r.gauge_family("iraft_role", "1 for the current role of this node", &["role"]),
+            leader: r.gauge_family("iraft_leader", "1 for the leader known to this node", &["leader"]),
+            commit_index: r.gauge("iraft_commit_index", "Highest log index known to be committed"),
+            applied_index: r.gauge("iraft_applied_index", "Highest log index applied to the state machine"),
+            last_index: r.gauge("iraft_last_index", "Index of the last entry in the local log"),
+            peer_match_index: r.gauge_family("iraft_peer_match_index", "Highest log index known to be replicated on the peer (leader only)", &["peer"]),
+            peer_lag: r.gauge_family("iraft_peer_replication_lag", "Entries the peer is behind the leader's last index (leader only)", &["peer"]),
+            peer_connected: r.gauge_family("iraft_peer_connected", "1 if the transport connection to the peer is up", &["peer"]),
+            elections: r.counter("iraft_elections_total", "Elections started by this node"),
+            proposals_accepted: r.counter("iraft_proposals_accepted_total", "Client proposals appended to the log"),
+            proposals_rejected: r.counter("iraft_proposals_rejected_total", "Client proposals rejected, e.g. because this node is not the leader"),
+            requests_forwarded: r.counter("iraft_requests_forwarded_total", "Client requests forwarded to the leader, counting retries"),
+            messages: r.counter_family("iraft_messages_total", "Raft messages by direction and event kind", &["direction", "kind"]),
+            invalid_messages: r.counter("iraft_invalid_messages_total", "Peer messages dropped because they failed validation"),
+            handshake_failures: r.counter("iraft_handshake_failures_total", "Connections closed because the handshake failed"),
+            bad_frames: r.counter("iraft_bad_frames_total", "Connections closed because a frame was oversized or could not be decoded"),
+            task_panics: r.counter("iraft_task_panics_total", "Connection tasks that panicked"),
+            accept_errors: r.counter_family("iraft_accept_errors_total", "Failed accepts by listener, e.g. when out of file descriptors", &["listener"]),
+            inbound_queue: r.gauge("iraft_inbound_queue_depth", "Peer messages waiting for the event loop"),
+            outbound_queue: r.gauge("iraft_outbound_queue_depth", "Messages waiting to be dispatched to peer queues"),
+            request_queue: r.gauge("iraft_request_queue_depth", "Client requests queued or in flight"),
+            peer_queue: r.gauge_family("iraft_peer_queue_depth", "Messages waiting to be sent to the peer", &["peer"]),
+            peer_write_batch: r.histogram("iraft_peer_write_batch_size", "Messages sent to a peer in one write", BATCH_BUCKETS),
+            requests_overloaded: r.counter("iraft_requests_overloaded_total", "Client requests rejected because the request queue was full"),
+            request_batch_size: r.histogram("iraft_request_batch_size", "Client requests handed to the node in one event loop turn", BATCH_BUCKETS),
+            client_connections: r.gauge("iraft_client_connections", "Open connections on the client listener"),
+            sessions: r.gauge("iraft_sessions", "Registered client sessions that have not expired"),
+            store_append_seconds: r.histogram("iraft_store_append_seconds", "Latency of Store::append", LATENCY_BUCKETS),
+            store_fsync_seconds: r.histogram("iraft_store_fsync_seconds", "Latency of Store::flush", LATENCY_BUCKETS),
+            store_batch_writes: r.histogram("iraft_store_batch_writes", "Writes grouped into one flush of the store", BATCH_BUCKETS),
+            store_batch_bytes: r.histogram("iraft_store_batch_bytes", "Bytes of log entries written per flush of the store", BYTES_BUCKETS),
+            registry: r,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        self.registry.render()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// 在绑定好的listener上提供 GET /metrics. 由调用方绑定, 端口被占用时启动就失败
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            //accept出错不结束监听
+            Err(e) => {
+                metrics.accept_errors.inc(&["metrics"]);
+                log::warn!("metrics accept failed: {}, retrying in {:?}", e, ACCEPT_BACKOFF);
+                runtime::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        runtime::spawn(async move {
+            if let Err(e) = handle_http(stream, &metrics).await {
+                log::debug!("metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
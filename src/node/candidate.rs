@@ -1,5 +1,6 @@
 use crate::node::{RoleNode, Node, ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX};
 use anyhow::Result;
+use crate::log::log::Command;
 use crate::message::{Message, Address, Event};
 use crate::node::follower::Follower;
 use crate::node::leader::Leader;
@@ -26,75 +27,141 @@ impl Candidate {
     }
 }
 
+impl Default for Candidate {
+    fn default() -> Self {
+        Candidate::new()
+    }
+}
+
 impl RoleNode<Candidate> {
     pub fn step(mut self, msg: Message) -> Result<Node> {
         //如果新消息term > 自己term, 说明有其他候选者节点在先,
         //这时候就不要竞争了,主动退让,让世界更和谐
         if msg.term > self.term {
             if let Address::Peer(from) = &msg.from {
-                return self.transfer_follower(msg.term, from.into())?.step(msg);
+                node_log!(info, self, "saw higher term {} from {}, stepping down", msg.term, from);
+                return self.transfer_follower(msg.term)?.step(msg);
             }
         }
 
         match msg.event {
-            Event::GrantVote => {
+            Event::GrantVote if msg.term == self.term => {
                 self.role.votes_count += 1;
+                node_log!(debug, self, "vote granted by {:?}, {} of {} votes", msg.from,
+                    self.role.votes_count, self.watershed());
                 if self.role.votes_count >= self.watershed() {
-                    let (lt, li, term) = (self.log.last_term, self.log.last_index, self.term);
-                    let mut node = self.transfer_leader()?;
-                    node = node.step(Message {
-                        term,
-                        from: Address::Local,
-                        to: Address::Peers,
-                        event: Event::Heartbeat {
-                            commit_index: li,
-                            commit_term: lt,
-                        },
-                    })?;
-                    return Ok(node);
+                    return self.transfer_leader();
                 }
             }
 
-            Event::Heartbeat{..} => {
+            //同任期内已经有人当选了
+            Event::Heartbeat{..} | Event::ReplicateEntries{..} if msg.term == self.term => {
                 if let Address::Peer(from) = &msg.from {
-                    return self.transfer_follower(msg.term, from.clone())?.step(msg);
+                    node_log!(info, self, "{} won the election", from);
+                    return self.transfer_follower(msg.term)?.step(msg);
                 }
             }
+            Event::ClientRequest { id, request } => self.redirect(msg.from, id, request)?,
             _ => {}
         }
 
-        Ok(Node::Candidate(self.into()))
+        Ok(Node::Candidate(self))
     }
 
     pub fn tick(mut self) -> Result<Node> {
-        println!("candidate tick");
+        node_log!(trace, self, "tick {}/{}", self.role.election_ticks + 1, self.role.election_timeout);
         self.role.election_ticks += 1;
         if self.role.election_ticks >= self.role.election_timeout {
-            self.term += 1;
-            self.role = Candidate::new();
-            self.send(Address::Peers, Event::SolicitVote {
-                last_term: self.log.last_term,
-                last_index: self.log.last_index,
-            })?;
-            Ok(Node::Candidate(self))
-        } else {
-            Ok(Node::Candidate(self))
+            node_log!(info, self, "election timed out, starting new election");
+            return self.campaign();
+        }
+        Ok(Node::Candidate(self))
+    }
+
+    /// 进入新任期, 给自己投一票并向所有节点拉票
+    pub(super) fn campaign(mut self) -> Result<Node> {
+        self.term += 1;
+        self.role = Candidate::new();
+        self.metrics.elections.inc();
+        self.save_hard_state(Some(self.id.clone()));
+        //单节点集群自己一票就够了
+        if self.role.votes_count >= self.watershed() {
+            return self.transfer_leader();
         }
+        self.send(Address::Peers, Event::SolicitVote {
+            last_term: self.log.last_term,
+            last_index: self.log.last_index,
+        })?;
+        Ok(Node::Candidate(self))
     }
 }
 
 impl RoleNode<Candidate> {
-    fn transfer_follower(mut self, term: u64, leader: String) -> Result<RoleNode<Follower>> {
+    fn transfer_follower(mut self, term: u64) -> Result<RoleNode<Follower>> {
         self.role.election_ticks = 0;
-        let mut node = self.transfer_role(Follower::new(Some(leader), None))?;
+        //同任期里已经投给了自己, 保存的投票不变, 不能再投给别人; 进入新任期才清掉投票
+        if term == self.term {
+            let voted_for = Some(self.id.clone());
+            return self.transfer_role(Follower::new(None, voted_for));
+        }
+        let mut node = self.transfer_role(Follower::new(None, None))?;
         node.term = term;
-        //log save
-        //abort all request
+        node.save_hard_state(None);
         Ok(node)
     }
 
-    fn transfer_leader(mut self) -> Result<Node> {
-        let mut node = self.transfer_role(super::Leader::new())?;
-        Ok(Node::Leader(node.into()))
+    fn transfer_leader(self) -> Result<Node> {
+        let leader = Leader::new(self.peers.keys(), self.log.last_index, &self.replication);
+        let mut node = self.transfer_role(leader)?;
+        node.notifier.reset_quorum();
+        //当选后先追加一条空log, 提交它就能把之前任期的log一起提交
+        node.propose(Command::Noop, None)?;
+        Ok(Node::Leader(node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::Event;
+    use crate::node::tests::{candidate, message};
+
+    /// 同任期里收到leader的心跳变回follower, 不能再投票给同任期的其他候选者
+    #[test]
+    fn no_second_vote_in_same_term() {
+        let mut node = candidate("1", &["2", "3"]);
+        let term = node.term();
+        node.ready().unwrap();
+        node = node.step(message(term, "2", Event::Heartbeat { commit_index: 0, commit_term: 0, read_seq: 0 })).unwrap();
+        assert_eq!(node.role_name(), "follower");
+        assert_eq!(node.status().voted_for.as_deref(), Some("1"));
+        //任期没变, 已经保存的投票不能被覆盖
+        assert_eq!(node.ready().unwrap().hard_state, None);
+
+        node = node.step(message(term, "3", Event::SolicitVote { last_index: 0, last_term: 0 })).unwrap();
+        let ready = node.ready().unwrap();
+        assert!(!ready.messages.iter().any(|m| matches!(m.event, Event::GrantVote)));
+        assert_eq!(node.status().voted_for.as_deref(), Some("1"));
+    }
+
+    /// 心跳到达前还没取走的Ready里, 保存的是本任期投给自己
+    #[test]
+    fn hard_state_keeps_self_vote() {
+        let mut node = candidate("1", &["2", "3"]);
+        let term = node.term();
+        node = node.step(message(term, "2", Event::Heartbeat { commit_index: 0, commit_term: 0, read_seq: 0 })).unwrap();
+        let hard_state = node.ready().unwrap().hard_state.unwrap();
+        assert_eq!((hard_state.term, hard_state.voted_for.as_deref()), (term, Some("1")));
+    }
+
+    /// 更高任期的消息让候选者退回follower, 新任期还没投过票
+    #[test]
+    fn vote_in_higher_term() {
+        let mut node = candidate("1", &["2", "3"]);
+        let term = node.term();
+        node = node.step(message(term + 1, "3", Event::SolicitVote { last_index: 0, last_term: 0 })).unwrap();
+        assert_eq!(node.role_name(), "follower");
+        let ready = node.ready().unwrap();
+        assert!(ready.messages.iter().any(|m| matches!(m.event, Event::GrantVote)));
+        assert_eq!(node.status().voted_for.as_deref(), Some("3"));
     }
 }
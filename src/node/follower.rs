@@ -1,5 +1,4 @@
 use anyhow::Result;
-use futures::channel::mpsc::UnboundedSender;
 use rand::Rng;
 
 use crate::message::{Event, Message, Address};
@@ -9,7 +8,7 @@ use crate::node::{ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX};
 
 #[derive(Debug)]
 pub struct Follower {
-    leader: Option<String>,
+    pub(super) leader: Option<String>,
     voted_for: Option<String>,
     //选举计时器
     leader_seen_ticks: u64,
@@ -30,80 +29,118 @@ impl Follower {
 }
 
 impl RoleNode<Follower> {
+    pub fn leader(&self) -> Option<&str> {
+        self.role.leader.as_deref()
+    }
+
+    pub fn voted_for(&self) -> Option<&str> {
+        self.role.voted_for.as_deref()
+    }
+
     pub fn tick(mut self) -> Result<Node> {
         //选举:
         // 等待超过随机时间时,将term加1(准备开始一个新任期),角色转换为候选者,并向所有节点发送'拉票'事件
         self.role.leader_seen_ticks += 1;
         if self.role.leader_seen_ticks >= self.role.leader_seen_timeout {
-            let (li, lt) = ((&self.log).last_index.clone(), (&self.log).last_term);
-            let mut node = self.transfer_role(Candidate::new())?;
-            node.term += 1;
-            node.log.save_metadata(node.term, None)?;
-            node.send(Address::Peers, Event::SolicitVote {
-                last_index: li,
-                last_term: lt,
-            });
-            Ok(Node::Candidate(node.into()))
+            node_log!(info, self, "leader not seen for {} ticks, starting election", self.role.leader_seen_ticks);
+            self.transfer_role(Candidate::new())?.campaign()
         } else {
             Ok(Node::Follower(self))
         }
     }
 
     pub fn step(mut self, msg: Message) -> Result<Node> {
-        //1, 如果msg.term > self.term: 说明是新一届的消息, 自己还follower(保存log,拒绝其他节点请求)
-        if msg.term > self.term || self.role.leader.is_none() {
-            self.term = msg.term;
-            self.log.save_metadata(self.term, None)?;
-            if let Address::Peer(ref from) = &msg.from {
-                self.role.leader = Some(from.clone());
-                return Node::Follower(self.into()).step(msg);
+        //1, 如果msg.term > self.term: 说明是新一届的消息, 进入新任期, 之前的leader和投票都作废
+        if msg.term > self.term {
+            if let Address::Peer(_) = &msg.from {
+                node_log!(info, self, "saw higher term {} from {:?}", msg.term, msg.from);
+                self.term = msg.term;
+                self.role = Follower::new(None, None);
+                self.save_hard_state(None);
+            }
+        }
+        //过期任期的节点消息, 不予搭理
+        if msg.term < self.term {
+            if let Address::Peer(_) = &msg.from {
+                node_log!(debug, self, "ignore stale {} from {:?} with term {}", msg.event.kind(), msg.from, msg.term);
+                return Ok(Node::Follower(self));
             }
         }
 
-        //2, 如果msg.from是自己已经承认的leader(msg.form==self.voted_for), 选举计时器清零(就不tick了)
+        //2, 本任期内第一个发来心跳/log的节点就是leader; 收到leader的消息, 选举计时器清零
         if let Address::Peer(from) = &msg.from {
-            let x = &(&self).role;
-            if Some(from) == x.voted_for.as_ref() {
+            if let Event::Heartbeat { .. } | Event::ReplicateEntries { .. } = &msg.event {
+                if self.role.leader.is_none() {
+                    node_log!(info, self, "following leader {}", from);
+                    self.role.leader = Some(from.clone());
+                }
+            }
+            if Some(from) == self.role.leader.as_ref() {
                 self.role.leader_seen_ticks = 0;
             }
         }
 
         //处理消息
         match msg.event {
-            Event::Heartbeat { commit_index, commit_term } => {
-                //todo some
-                println!("认主成功, 心跳加速..");
+            Event::Heartbeat { commit_index, commit_term, read_seq } => {
+                let has_committed = self.log.has(commit_index, commit_term);
+                node_log!(debug, self, "heartbeat from {:?}, commit_index={} has_committed={}",
+                    msg.from, commit_index, has_committed);
+                if has_committed && commit_index > self.log.commit_index {
+                    self.commit_to(commit_index)?;
+                }
                 self.send(msg.from, Event::ConfirmLeader {
                     commit_index,
-                    has_committed: true,
+                    has_committed,
+                    read_seq,
                 })?;
             }
+            Event::ReplicateEntries { base_index, base_term, entries } => {
+                if !self.log.has(base_index, base_term) {
+                    node_log!(debug, self, "reject entries from {:?}, missing base {}/{}", msg.from, base_index, base_term);
+                    self.send(msg.from, Event::RejectEntries { base_index, last_index: self.log.last_index })?;
+                } else {
+                    let last_index = base_index + entries.len() as u64;
+                    self.log.splice(entries)?;
+                    self.send(msg.from, Event::AcceptEntries { last_index })?;
+                }
+            }
             Event::SolicitVote { last_index, last_term } => {
                 //处理拉票请求
-                //1, 如果msg.form不是自己已经认定的leader, 不予搭理
+                //1, 如果本任期已经投给了别人, 不予搭理
                 if let Some(voted_for) = &self.role.voted_for {
                     if msg.from != Address::Peer(voted_for.clone()) {
-                        return Ok(Node::Follower(self.into()));
+                        node_log!(debug, self, "ignore vote request from {:?}, already voted for {}", msg.from, voted_for);
+                        return Ok(Node::Follower(self));
                     }
                 }
                 //2, msg.last_term < 自己term , 不予搭理
                 if last_term < self.log.last_term {
-                    return Ok(Node::Follower(self.into()));
+                    node_log!(debug, self, "ignore vote request from {:?}, stale last_term {}", msg.from, last_term);
+                    return Ok(Node::Follower(self));
                 }
                 //3, term相等 并且 index < 自己的, 不予搭理
-                if last_term < self.log.last_term && last_index < self.log.last_index {
-                    return Ok(Node::Follower(self.into()));
+                if last_term == self.log.last_term && last_index < self.log.last_index {
+                    node_log!(debug, self, "ignore vote request from {:?}, stale last_index {}", msg.from, last_index);
+                    return Ok(Node::Follower(self));
                 }
                 //4, 发送赞成消息
-                self.send((&msg.from).clone(), Event::GrantVote)?;
                 if let Address::Peer(from) = &msg.from {
+                    node_log!(info, self, "voted for {}", from);
                     self.role.voted_for = Some(from.clone());
-                    self.log.save_metadata(self.term, Some(&from))?
+                    self.save_hard_state(Some(from.clone()));
                 }
+                self.send(msg.from, Event::GrantVote)?;
+            }
+            //leader要把位置让给自己, 马上开始选举
+            Event::TimeoutNow if msg.from.peer() == self.role.leader.as_deref() => {
+                node_log!(info, self, "leadership transfer requested by {:?}, starting election", msg.from);
+                return self.transfer_role(Candidate::new())?.campaign();
             }
+            Event::ClientRequest { id, request } => self.redirect(msg.from, id, request)?,
             _ => (),
         }
 
-        Ok(Node::Follower(self.into()))
+        Ok(Node::Follower(self))
     }
-}
\ No newline at end of file
+}
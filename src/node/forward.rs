@@ -0,0 +1,168 @@
+//! 请求转发: 不是leader的节点把客户端的提议和线性一致读转发给leader, 再把leader的回复转给客户端.
+//! 转发的请求和回复都是节点之间的ClientRequest/ClientResponse消息, 请求id沿用本节点的客户端请求id
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::message::{Address, Event, Request, RequestError, Response};
+use crate::node::leader::Leader;
+use crate::node::{Role, RoleNode};
+
+#[derive(Debug, Default)]
+pub struct Forwards {
+    //转发超时, None时不转发
+    timeout: Option<Duration>,
+    //本节点转发的请求, 客户端请求id -> 转发状态. id按到达顺序递增, 按顺序发出
+    outgoing: BTreeMap<Vec<u8>, Forward>,
+    //leader上处理中的转发请求, 本地id -> (peer, peer上的请求id)
+    incoming: HashMap<Vec<u8>, (String, Vec<u8>)>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Forward {
+    request: Request,
+    //超过这个时间还没有结果就失败
+    deadline: Instant,
+    //发给了哪个leader, 还没发或者被退回了是None
+    sent_to: Option<String>,
+}
+
+impl Forwards {
+    pub fn new(timeout: Option<Duration>) -> Forwards {
+        Forwards { timeout, ..Forwards::default() }
+    }
+
+    /// 最早到期的转发请求的截止时间, 没有转发中的请求时是None
+    pub fn deadline(&self) -> Option<Instant> {
+        self.outgoing.values().map(|forward| forward.deadline).min()
+    }
+
+    /// 回复的是peer转发来的请求时, 取出要发回的peer和它的请求id
+    pub(super) fn reply_to(&mut self, id: &[u8]) -> Option<(String, Vec<u8>)> {
+        self.incoming.remove(id)
+    }
+
+    /// leader收到peer转发的请求, 分配本地id. 本地的客户端请求id是8字节, 这里的id更长, 不会冲突
+    fn accept(&mut self, peer: String, id: Vec<u8>) -> Vec<u8> {
+        self.next_id += 1;
+        let local = format!("forward-{}", self.next_id).into_bytes();
+        self.incoming.insert(local.clone(), (peer, id));
+        local
+    }
+}
+
+impl<R: Role> RoleNode<R> {
+    /// 不是leader时收到的请求: 客户端的提议和读请求开启了转发就等着转给leader, 其他的拒绝;
+    /// peer转发来的退回去, 不再转发
+    pub(super) fn redirect(&mut self, from: Address, id: Vec<u8>, request: Request) -> Result<()> {
+        let leader = self.role.leader(&self.id).map(String::from);
+        match (from, self.forwards.timeout) {
+            (Address::Peer(peer), _) => self.send(Address::Peer(peer), Event::ClientResponse {
+                id,
+                response: Err(RequestError::NotLeader { leader }),
+            }),
+            (_, Some(timeout)) if forwardable(&request) => {
+                node_log!(debug, self, "forward request {:?} to leader {:?}", id, leader);
+                let deadline = Instant::now() + timeout;
+                self.forwards.outgoing.insert(id, Forward { request, deadline, sent_to: None });
+                self.send_forwards()
+            }
+            _ => self.reject_client(id, leader),
+        }
+    }
+
+    /// 知道leader时把还没发出的转发请求发给它
+    pub(super) fn send_forwards(&mut self) -> Result<()> {
+        let leader = match self.role.leader(&self.id) {
+            Some(leader) if leader != self.id => leader.to_string(),
+            _ => return Ok(()),
+        };
+        let mut requests = vec![];
+        for (id, forward) in self.forwards.outgoing.iter_mut() {
+            if forward.sent_to.is_none() {
+                forward.sent_to = Some(leader.clone());
+                requests.push((id.clone(), forward.request.clone()));
+            }
+        }
+        for (id, request) in requests {
+            self.metrics.requests_forwarded.inc();
+            self.send(Address::Peer(leader.clone()), Event::ClientRequest { id, request })?;
+        }
+        Ok(())
+    }
+
+    /// leader对转发请求的回复. 没有执行的请求等下次再转发; 有结果的, 包括结果未知的, 转给客户端.
+    /// 读请求没有副作用, 带会话的命令不会重复应用, leader下台了也可以重试
+    pub(super) fn forwarded(&mut self, from: &str, id: Vec<u8>, response: Response) -> Result<()> {
+        let forward = match self.forwards.outgoing.get_mut(&id) {
+            Some(forward) if forward.sent_to.as_deref() == Some(from) => forward,
+            _ => {
+                node_log!(debug, self, "ignore response {:?} from {}, not forwarded there", response, from);
+                return Ok(());
+            }
+        };
+        let retry = match &response {
+            Err(RequestError::NotLeader { .. }) | Err(RequestError::Unavailable(_)) => true,
+            Err(RequestError::LeadershipLost) =>
+                matches!(forward.request, Request::Read(_) | Request::SessionPropose { .. }),
+            _ => false,
+        };
+        if retry {
+            forward.sent_to = None;
+            node_log!(debug, self, "forwarded request bounced by {}: {:?}", from, response);
+            return Ok(());
+        }
+        self.forwards.outgoing.remove(&id);
+        self.respond(id, response)
+    }
+
+    /// 转发的请求到了截止时间: 没有转发出去的和leader拒绝了的说明没有leader, 转发出去还没回复的结果未知
+    pub(super) fn expire_forwards(&mut self, now: Instant) -> Result<()> {
+        let expired: Vec<Vec<u8>> = self.forwards.outgoing.iter()
+            .filter(|(_, forward)| forward.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            let forward = self.forwards.outgoing.remove(&id).unwrap();
+            node_log!(debug, self, "forwarded request {:?} timed out, sent to {:?}", id, forward.sent_to);
+            match forward.sent_to {
+                Some(_) => self.respond(id, Err(RequestError::Timeout))?,
+                None => {
+                    let leader = self.role.leader(&self.id).map(String::from);
+                    self.reject_client(id, leader)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RoleNode<Leader> {
+    /// peer转发来的请求, 和本地客户端的请求一样处理, 回复时发回给peer
+    pub(super) fn forwarded_request(&mut self, peer: String, id: Vec<u8>, request: Request) -> Result<()> {
+        let id = self.forwards.accept(peer, id);
+        self.client_request(id, request)
+    }
+
+    /// 自己成了leader, 还没发出的转发请求自己处理. 已经发给旧leader的等它的回复:
+    /// 提议可能已经在旧leader上提交了, 不能再提一次
+    pub(super) fn serve_forwards(&mut self) -> Result<()> {
+        let unsent: Vec<Vec<u8>> = self.forwards.outgoing.iter()
+            .filter(|(_, forward)| forward.sent_to.is_none())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in unsent {
+            let forward = self.forwards.outgoing.remove(&id).unwrap();
+            self.client_request(id, forward.request)?;
+        }
+        Ok(())
+    }
+}
+
+/// 转发的只有提议和读请求, 运维请求要发给leader
+pub(super) fn forwardable(request: &Request) -> bool {
+    matches!(request, Request::Propose(_) | Request::Read(_) | Request::RegisterSession | Request::SessionPropose { .. })
+}
@@ -1,15 +1,73 @@
-use crate::node::{RoleNode, Node, HEARTBEAT_INTERVAL};
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::node::{RoleNode, Node, HEARTBEAT_INTERVAL, ELECTION_TIMEOUT_MAX};
 use anyhow::Result;
-use crate::message::{Message, Address, Event};
+use crate::log::log::{Command, ConfigChange};
+use crate::message::{Message, Address, Event, Request, RequestError, Response};
+use crate::node::progress::{Progress, ProgressState};
+use crate::node::ready::ReadState;
+use crate::conf::ReplicationConfig;
 
 #[derive(Debug)]
 pub struct Leader {
     heartbeat_ticks: u64,
+    //每个peer的复制进度
+    pub(super) progress: HashMap<String, Progress>,
+    //有新追加的log还没发给peer, 取Ready时一起发, 同一轮的提议合并成一次复制
+    unreplicated: bool,
+    //最后一次收到peer消息的时间
+    pub(super) peer_last_contact: HashMap<String, Instant>,
+    //还没应用的客户端请求, log index -> 请求id
+    proposals: HashMap<u64, Vec<u8>>,
+    //最后一条成员变更log的index, 提交之前不接受新的变更
+    pending_config_index: u64,
+
+    //线性一致读: 每个读请求分配一个read_seq随心跳发出, 多数节点回应后才能读
+    read_seq: u64,
+    peer_read_seq: HashMap<String, u64>,
+    reads: Vec<PendingRead>,
+
+    //进行中的leader转移
+    transfer: Option<Transfer>,
+
+    //每个选举超时检查一次, 期间联系上的peer加上自己不够多数就通知失去多数派
+    quorum_ticks: u64,
+    quorum_contacts: HashSet<String>,
+}
+
+#[derive(Debug)]
+struct PendingRead {
+    id: Vec<u8>,
+    query: Vec<u8>,
+    seq: u64,
+    //收到请求时的commit index, 应用到这里之后才能读
+    index: u64,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    id: Vec<u8>,
+    target: String,
+    ticks: u64,
 }
 
 impl Leader {
-    pub fn new() -> Leader {
-        Leader { heartbeat_ticks: 0 }
+    pub fn new<'a>(peers: impl Iterator<Item=&'a String>, last_index: u64, conf: &ReplicationConfig) -> Leader {
+        Leader {
+            heartbeat_ticks: 0,
+            progress: peers.map(|p| (p.to_string(), Progress::new(last_index + 1, conf))).collect(),
+            unreplicated: false,
+            peer_last_contact: HashMap::new(),
+            proposals: HashMap::new(),
+            pending_config_index: 0,
+            read_seq: 0,
+            peer_read_seq: HashMap::new(),
+            reads: vec![],
+            transfer: None,
+            quorum_ticks: 0,
+            quorum_contacts: HashSet::new(),
+        }
     }
 }
 
@@ -21,10 +79,28 @@ impl RoleNode<Leader> {
             //持续心跳
             if self.role.heartbeat_ticks >= HEARTBEAT_INTERVAL {
                 self.role.heartbeat_ticks = 0;
-                self.send(Address::Peers, Event::Heartbeat {
-                    commit_index: self.log.commit_index,
-                    commit_term: self.log.commit_term,
-                })?;
+                self.heartbeat()?;
+            }
+        }
+        self.role.quorum_ticks += 1;
+        if self.role.quorum_ticks >= ELECTION_TIMEOUT_MAX {
+            let contacts = std::mem::take(&mut self.role.quorum_contacts);
+            let heard = contacts.iter().filter(|p| self.peers.contains_key(*p)).count();
+            let quorum = heard as u64 + 1 >= self.watershed();
+            if !quorum {
+                node_log!(warn, self, "heard from {} of {} peers in the last {} ticks, quorum lost",
+                    heard, self.peers.len(), self.role.quorum_ticks);
+            }
+            self.notifier.quorum(self.term, quorum);
+            self.role.quorum_ticks = 0;
+        }
+        //转移超时了还没完成, 继续当leader
+        if let Some(transfer) = &mut self.role.transfer {
+            transfer.ticks += 1;
+            if transfer.ticks > ELECTION_TIMEOUT_MAX {
+                let transfer = self.role.transfer.take().unwrap();
+                node_log!(warn, self, "leadership transfer to {} timed out", transfer.target);
+                self.respond(transfer.id, Err(RequestError::Timeout))?;
             }
         }
         Ok(Node::Leader(self))
@@ -34,17 +110,474 @@ impl RoleNode<Leader> {
         //有人起义成功了, 不做无为抵抗
         if msg.term > self.term {
             if let Address::Peer(from) = &msg.from {
-                let mut node = self.transfer_role(super::Follower::new(Some(from.clone()), None))?;
+                node_log!(info, self, "saw higher term {} from {}, stepping down", msg.term, from);
+                self.abort_requests()?;
+                let mut node = self.transfer_role(super::Follower::new(None, None))?;
+                node.term = msg.term;
+                node.save_hard_state(None);
                 return node.step(msg);
             }
         }
+        if msg.term < self.term {
+            if let Address::Peer(_) = &msg.from {
+                node_log!(debug, self, "ignore stale {} from {:?} with term {}", msg.event.kind(), msg.from, msg.term);
+                return Ok(Node::Leader(self));
+            }
+        }
 
-        match msg.event {
-            Event::ConfirmLeader { commit_index, has_committed } => {
-                println!("从{:?}收到确认:{},{}", msg.from, commit_index, has_committed);
+        if let Address::Peer(from) = &msg.from {
+            self.role.peer_last_contact.insert(from.clone(), Instant::now());
+            self.role.quorum_contacts.insert(from.clone());
+        }
+
+        match (msg.event, msg.from) {
+            (Event::ConfirmLeader { commit_index, has_committed, read_seq }, Address::Peer(from)) => {
+                node_log!(debug, self, "leadership confirmed by {}: commit_index={}, has_committed={}",
+                    from, commit_index, has_committed);
+                if read_seq > self.role.peer_read_seq.get(&from).copied().unwrap_or(0) {
+                    self.role.peer_read_seq.insert(from.clone(), read_seq);
+                    self.serve_reads()?;
+                }
+                let behind = match self.role.progress.get_mut(&from) {
+                    Some(pr) => {
+                        pr.heartbeat_response();
+                        pr.match_index < self.log.last_index
+                    }
+                    None => false,
+                };
+                if !has_committed || behind {
+                    self.replicate(&from)?;
+                }
+            }
+            (Event::AcceptEntries { last_index }, Address::Peer(from)) => {
+                if self.role.progress.get_mut(&from).is_some_and(|pr| pr.accepted(last_index)) {
+                    self.commit()?;
+                    //窗口腾出了位置, 接着发
+                    self.replicate(&from)?;
+                }
+                self.maybe_timeout_now(&from)?;
+            }
+            (Event::RejectEntries { base_index, last_index }, Address::Peer(from)) => {
+                if self.role.progress.get_mut(&from).is_some_and(|pr| pr.rejected(base_index, last_index)) {
+                    node_log!(debug, self, "{} rejected entries after {}, probing from {}",
+                        from, base_index, self.role.progress[&from].next_index);
+                    self.replicate(&from)?;
+                }
             }
-            _ => println!("~~~"),
+            (Event::ClientRequest { id, request }, Address::Peer(from)) => self.forwarded_request(from, id, request)?,
+            (Event::ClientRequest { id, request }, _) => self.client_request(id, request)?,
+            //连接断开时在途的消息可能丢了, 恢复后从peer的进度重新探测
+            (Event::PeerConnection { peer, connected }, Address::Local) if self.peers.contains_key(&peer) => {
+                if let Some(pr) = self.role.progress.get_mut(&peer) {
+                    if pr.state == ProgressState::Replicate {
+                        pr.become_probe();
+                    }
+                }
+                if connected {
+                    self.replicate(&peer)?;
+                }
+            }
+            //在途的日志复制没有发出去, 不会有回应, 不重新探测的话inflight一直是满的
+            (Event::ReplicationDropped { peer }, Address::Local) if self.peers.contains_key(&peer) => {
+                //快照不是通过这个队列发的
+                if let Some(pr) = self.role.progress.get_mut(&peer).filter(|pr| pr.state != ProgressState::Snapshot) {
+                    pr.become_probe();
+                    node_log!(debug, self, "replication to {} dropped, probing from {}", peer, self.role.progress[&peer].next_index);
+                    self.replicate(&peer)?;
+                }
+            }
+            (event, from) => node_log!(debug, self, "ignore {:?} from {:?}", event, from),
         }
-        Ok(Node::Leader(self.into()))
+        Ok(Node::Leader(self))
     }
-}
\ No newline at end of file
+
+    pub(super) fn client_request(&mut self, id: Vec<u8>, request: Request) -> Result<()> {
+        if let Some(transfer) = &self.role.transfer {
+            let reason = format!("leadership transfer to {} in progress", transfer.target);
+            self.metrics.proposals_rejected.inc();
+            return self.respond(id, Err(RequestError::Unavailable(reason)));
+        }
+        match request {
+            Request::Propose(command) => {
+                self.propose(Command::State(command), Some(id))?;
+                self.metrics.proposals_accepted.inc();
+            }
+            //会话按log里的时间过期, 时间由leader写入
+            Request::RegisterSession => {
+                self.propose(Command::RegisterSession { time: log_time() }, Some(id))?;
+                self.metrics.proposals_accepted.inc();
+            }
+            Request::SessionPropose { client_id, seq, command } => {
+                self.propose(Command::SessionState { client_id, seq, time: log_time(), command }, Some(id))?;
+                self.metrics.proposals_accepted.inc();
+            }
+            Request::Read(query) => {
+                self.role.read_seq += 1;
+                self.role.reads.push(PendingRead {
+                    id,
+                    query,
+                    seq: self.role.read_seq,
+                    index: self.log.commit_index,
+                });
+                //马上发一轮心跳确认自己还是leader
+                self.heartbeat()?;
+                self.serve_reads()?;
+            }
+            Request::ChangeMembership(change) => {
+                if let Err(reason) = self.check_config_change(&change) {
+                    self.metrics.proposals_rejected.inc();
+                    return self.respond(id, Err(RequestError::Rejected(reason)));
+                }
+                self.role.pending_config_index = self.propose(Command::Membership(change), Some(id))?;
+                self.metrics.proposals_accepted.inc();
+            }
+            Request::TransferLeadership(target) => {
+                let target = match target {
+                    Some(target) => target,
+                    //选复制进度最快的
+                    None => match self.role.progress.iter().max_by_key(|(_, pr)| pr.match_index) {
+                        Some((peer, _)) => peer.clone(),
+                        None => return self.respond(id, Err(RequestError::Rejected("no peer to transfer leadership to".to_string()))),
+                    },
+                };
+                if target == self.id {
+                    return self.respond(id, Ok(vec![]));
+                }
+                if !self.peers.contains_key(&target) {
+                    return self.respond(id, Err(RequestError::Rejected(format!("unknown peer {}", target))));
+                }
+                node_log!(info, self, "transferring leadership to {}", target);
+                self.role.transfer = Some(Transfer { id, target: target.clone(), ticks: 0 });
+                self.replicate(&target)?;
+                self.maybe_timeout_now(&target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_config_change(&self, change: &ConfigChange) -> std::result::Result<(), String> {
+        if self.role.pending_config_index > self.log.commit_index {
+            return Err("another membership change is in progress".to_string());
+        }
+        match change {
+            ConfigChange::AddPeer { id, .. } if *id == self.id || self.peers.contains_key(id) =>
+                Err(format!("{} is already a member", id)),
+            ConfigChange::RemovePeer { id } if *id == self.id =>
+                Err("cannot remove the leader, transfer leadership first".to_string()),
+            ConfigChange::RemovePeer { id } if !self.peers.contains_key(id) =>
+                Err(format!("{} is not a member", id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 转移目标的log已经追上, 让它马上选举
+    fn maybe_timeout_now(&mut self, peer: &str) -> Result<()> {
+        if let Some(transfer) = &self.role.transfer {
+            if transfer.target == peer
+                && self.role.progress.get(peer).map_or(0, |pr| pr.match_index) == self.log.last_index {
+                self.send(Address::Peer(peer.to_string()), Event::TimeoutNow)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 下台时还没完成的请求结果未知, 让客户端自己重试; 转移leader算成功
+    fn abort_requests(&mut self) -> Result<()> {
+        for (_, id) in std::mem::take(&mut self.role.proposals) {
+            self.respond(id, Err(RequestError::LeadershipLost))?;
+        }
+        for read in std::mem::take(&mut self.role.reads) {
+            self.respond(read.id, Err(RequestError::LeadershipLost))?;
+        }
+        if let Some(transfer) = self.role.transfer.take() {
+            self.respond(transfer.id, Ok(vec![]))?;
+        }
+        Ok(())
+    }
+
+    fn heartbeat(&mut self) -> Result<()> {
+        self.send_replicate(Address::Peers, Event::Heartbeat {
+            commit_index: self.log.commit_index,
+            commit_term: self.log.commit_term,
+            read_seq: self.role.read_seq,
+        })
+    }
+
+    /// 追加一条log, 返回log index. 有id时应用后回复客户端.
+    /// 取Ready时再复制给所有peer, 这之前的提议一起写盘, 一起发送
+    pub fn propose(&mut self, command: Command, id: Option<Vec<u8>>) -> Result<u64> {
+        let entry = self.log.append(self.term, command);
+        if let Some(id) = id {
+            self.role.proposals.insert(entry.index, id);
+        }
+        self.role.unreplicated = true;
+        Ok(entry.index)
+    }
+
+    /// 把攒下的新log复制给所有peer
+    pub(super) fn broadcast(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.role.unreplicated) {
+            return Ok(());
+        }
+        let peers: Vec<String> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            self.replicate(&peer)?;
+        }
+        Ok(())
+    }
+
+    /// 把next_index之后的log复制给peer: probe状态发一条消息探测, replicate状态在窗口内一直发到最新
+    fn replicate(&mut self, peer: &str) -> Result<()> {
+        if self.peers_down.contains(peer) {
+            return Ok(());
+        }
+        loop {
+            let (state, next) = match self.role.progress.get(peer) {
+                Some(pr) if !pr.is_paused() => (pr.state, pr.next_index),
+                _ => return Ok(()),
+            };
+            //已经跟上了, 不用发空消息
+            if state == ProgressState::Replicate && next > self.log.last_index {
+                return Ok(());
+            }
+            //需要的log已经压缩了, 只能发快照
+            if next < self.log.first_index() {
+                node_log!(warn, self, "entry {} for {} before first index {}, waiting for snapshot",
+                    next, peer, self.log.first_index());
+                let snapshot_index = self.log.commit_index;
+                if let Some(pr) = self.role.progress.get_mut(peer) {
+                    pr.become_snapshot(snapshot_index);
+                }
+                return Ok(());
+            }
+            let base_index = next - 1;
+            let base_term = match self.log.get(base_index) {
+                Some(base) => base.term,
+                None if base_index == 0 => 0,
+                //next_index越过了log末尾, 从已确认的位置重新找
+                None => {
+                    node_log!(warn, self, "base entry {} for {} not in log, probing again", base_index, peer);
+                    if let Some(pr) = self.role.progress.get_mut(peer) {
+                        pr.become_probe();
+                    }
+                    return Ok(());
+                }
+            };
+            //一条消息至少带一条log, 最多max_msg_bytes字节
+            let mut entries = vec![];
+            let mut bytes = 0;
+            for entry in self.log.scan(next..) {
+                if !entries.is_empty() && bytes + entry.size() > self.replication.max_msg_bytes {
+                    break;
+                }
+                bytes += entry.size();
+                entries.push(entry.clone());
+            }
+            let last_index = base_index + entries.len() as u64;
+            node_log!(trace, self, "replicate {} entries to {} from base {}", entries.len(), peer, base_index);
+            self.send_replicate(Address::Peer(peer.to_string()), Event::ReplicateEntries { base_index, base_term, entries })?;
+            if let Some(pr) = self.role.progress.get_mut(peer) {
+                pr.sent(last_index, bytes);
+            }
+            if state != ProgressState::Replicate {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 复制消息和自己写盘并行, 不等这一轮的Ready持久化就发出去
+    fn send_replicate(&mut self, to: Address, event: Event) -> Result<()> {
+        let msg = Message { term: self.term, from: Address::Local, to, event };
+        node_log!(trace, self, "send {:?}", msg);
+        self.ready.replicate_messages.push(msg);
+        Ok(())
+    }
+
+    /// 多数节点都有了的log就可以提交, 只提交本任期的log(论文5.4.2).
+    /// 自己的log和复制并行写盘, 写完(stable_index)才算自己有了
+    pub(super) fn commit(&mut self) -> Result<()> {
+        let mut last_indexes: Vec<u64> = self.role.progress.values().map(|pr| pr.match_index).collect();
+        last_indexes.push(self.log.stable_index);
+        last_indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let quorum_index = last_indexes[self.watershed() as usize - 1];
+
+        if quorum_index > self.log.commit_index && self.log.has(quorum_index, self.term) {
+            self.commit_to(quorum_index)?;
+            node_log!(debug, self, "committed up to {}", quorum_index);
+            self.sync_progress();
+            self.serve_reads()?;
+        }
+        Ok(())
+    }
+
+    /// 调用方把from之后已提交的log应用完了, 回复提交它们的客户端
+    pub(super) fn applied(&mut self, from: u64, results: Vec<(u64, Response)>) -> Result<()> {
+        let mut results: HashMap<u64, Response> = results.into_iter().collect();
+        for index in from + 1..=self.applied_index {
+            if let Some(id) = self.role.proposals.remove(&index) {
+                //空log和成员变更没有结果
+                let response = results.remove(&index).unwrap_or_else(|| Ok(vec![]));
+                self.respond(id, response)?;
+            }
+        }
+        self.serve_reads()
+    }
+
+    /// 成员变更后, 复制进度跟着peers增删
+    fn sync_progress(&mut self) {
+        let next = self.log.last_index + 1;
+        let conf = &self.replication;
+        for peer in self.peers.keys() {
+            self.role.progress.entry(peer.clone()).or_insert_with(|| Progress::new(next, conf));
+        }
+        let peers = &self.peers;
+        self.role.progress.retain(|p, _| peers.contains_key(p));
+        self.role.peer_last_contact.retain(|p, _| peers.contains_key(p));
+        self.role.peer_read_seq.retain(|p, _| peers.contains_key(p));
+    }
+
+    /// 处理已经确认了leader身份, 并且状态机已经追上的读请求
+    fn serve_reads(&mut self) -> Result<()> {
+        //本任期还没提交过log时, commit index可能落后, 先不读
+        if self.role.reads.is_empty() || self.log.commit_term != self.term {
+            return Ok(());
+        }
+        let mut seqs: Vec<u64> = self.role.peer_read_seq.values().copied().collect();
+        seqs.push(self.role.read_seq);
+        seqs.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed_seq = seqs.get(self.watershed() as usize - 1).copied().unwrap_or(0);
+
+        let applied_index = self.applied_index;
+        let (confirmed, pending) = std::mem::take(&mut self.role.reads).into_iter()
+            .partition(|r| r.seq <= confirmed_seq && r.index <= applied_index);
+        self.role.reads = pending;
+        for read in confirmed {
+            self.ready.reads.push(ReadState { id: read.id, query: read.query });
+        }
+        Ok(())
+    }
+}
+
+/// 写进log的时间, unix毫秒
+fn log_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::tests::{candidate, message};
+
+    /// 处理完攒下的Ready, 提交的State命令按原样作为结果
+    fn settle(node: &mut Node) {
+        while node.has_ready() {
+            let ready = node.ready().unwrap();
+            let results = ready.committed_entries.iter().filter_map(|e| match &e.command {
+                Command::State(command) => Some((e.index, Ok(command.clone()))),
+                _ => None,
+            }).collect();
+            node.advance(ready.checkpoint, results, vec![]).unwrap();
+        }
+    }
+
+    /// 当选并提交了自己任期的空log, peers都已经进入replicate状态
+    fn leader(id: &str, peers: &[&str]) -> Node {
+        let mut node = candidate(id, peers);
+        let term = node.term();
+        node = node.step(message(term, peers[0], Event::GrantVote)).unwrap();
+        assert_eq!(node.role_name(), "leader");
+        settle(&mut node);
+        for peer in peers {
+            node = node.step(message(term, peer, Event::AcceptEntries { last_index: 1 })).unwrap();
+        }
+        settle(&mut node);
+        node
+    }
+
+    fn propose(id: u8) -> Message {
+        Message {
+            term: 0,
+            from: Address::Client,
+            to: Address::Local,
+            event: Event::ClientRequest { id: vec![id], request: Request::Propose(vec![id]) },
+        }
+    }
+
+    /// 一轮里的提议一起写盘, 每个peer一条复制消息, 各自有自己的index和回复
+    #[test]
+    fn proposals_batched() {
+        let mut node = leader("1", &["2", "3"]);
+        let term = node.term();
+        for i in 0..5 {
+            node = node.step(propose(i)).unwrap();
+        }
+        let ready = node.ready().unwrap();
+        let indexes: Vec<u64> = ready.entries.iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![2, 3, 4, 5, 6]);
+        let mut appends: Vec<(String, usize)> = ready.replicate_messages.iter().filter_map(|m| match (&m.to, &m.event) {
+            (Address::Peer(peer), Event::ReplicateEntries { entries, .. }) => Some((peer.clone(), entries.len())),
+            _ => None,
+        }).collect();
+        appends.sort();
+        assert_eq!(appends, vec![("2".to_string(), 5), ("3".to_string(), 5)]);
+        node.advance(ready.checkpoint, vec![], vec![]).unwrap();
+
+        node = node.step(message(term, "2", Event::AcceptEntries { last_index: 6 })).unwrap();
+        let ready = node.ready().unwrap();
+        assert_eq!(ready.committed_entries.len(), 5);
+        let results = ready.committed_entries.iter().map(|e| match &e.command {
+            Command::State(command) => (e.index, Ok(command.clone())),
+            c => panic!("unexpected {:?}", c),
+        }).collect();
+        node.advance(ready.checkpoint, results, vec![]).unwrap();
+        let ready = node.ready().unwrap();
+        let mut replies: Vec<(Vec<u8>, Response)> = ready.messages.into_iter().filter_map(|m| match m.event {
+            Event::ClientResponse { id, response } => Some((id, response)),
+            _ => None,
+        }).collect();
+        replies.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(replies, (0..5).map(|i| (vec![i], Ok(vec![i]))).collect::<Vec<_>>());
+    }
+
+    /// transport丢掉了发往peer的复制, leader从peer确认过的位置重新探测
+    #[test]
+    fn dropped_replication_probes_again() {
+        let mut node = leader("1", &["2", "3"]);
+        for i in 0..3 {
+            node = node.step(propose(i)).unwrap();
+        }
+        settle(&mut node);
+
+        node = node.step(Message {
+            term: 0,
+            from: Address::Local,
+            to: Address::Local,
+            event: Event::ReplicationDropped { peer: "2".to_string() },
+        }).unwrap();
+        let ready = node.ready().unwrap();
+        let appends: Vec<(&Address, u64, usize)> = ready.replicate_messages.iter().filter_map(|m| match &m.event {
+            Event::ReplicateEntries { base_index, entries, .. } => Some((&m.to, *base_index, entries.len())),
+            _ => None,
+        }).collect();
+        assert_eq!(appends, vec![(&Address::Peer("2".to_string()), 1, 3)]);
+    }
+
+    /// 复制消息不等本地写盘就发出, 自己的log写完(checkpoint里的stable_index)才算进多数
+    #[test]
+    fn own_entries_count_after_stable() {
+        let mut node = leader("1", &["2", "3"]);
+        let term = node.term();
+        node = node.step(propose(0)).unwrap();
+        let ready = node.ready().unwrap();
+        assert_eq!(ready.entries.len(), 1);
+        assert_eq!(ready.replicate_messages.len(), 2);
+        assert_eq!(ready.checkpoint.stable_index, 2);
+
+        //只有一个peer写完, 自己还没写完, 凑不够多数
+        node = node.step(message(term, "2", Event::AcceptEntries { last_index: 2 })).unwrap();
+        assert_eq!(node.status().commit_index, 1);
+
+        node.advance(ready.checkpoint, vec![], vec![]).unwrap();
+        assert_eq!(node.status().commit_index, 2);
+    }
+}
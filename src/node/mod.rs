@@ -1,15 +1,38 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use futures::channel::mpsc::UnboundedSender;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::message::{Address, Event, Message};
+use crate::message::{Address, Event, Message, RequestError, Response};
 use crate::node::candidate::Candidate;
 use crate::node::follower::Follower;
+use crate::node::forward::Forwards;
 use crate::node::leader::Leader;
-use crate::log::log::Log;
+use crate::node::progress::ProgressState;
+use crate::node::ready::{Checkpoint, HardState, Ready};
+use crate::log::log::{Command, ConfigChange, Log};
+use crate::conf::ReplicationConfig;
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+
+/// 带上节点上下文(id, term, role)打日志, 用法: node_log!(debug, self, "...", args)
+macro_rules! node_log {
+    ($lvl:ident, $node:expr, $($arg:tt)+) => {
+        log::$lvl!(
+            "[node={} term={} role={}] {}",
+            $node.id, $node.term, $node.role_name(), format_args!($($arg)+)
+        )
+    };
+}
 
 pub mod leader;
 pub mod follower;
 pub mod candidate;
+pub mod forward;
+pub mod progress;
+pub mod ready;
 
 
 /// 心跳间隔
@@ -18,8 +41,84 @@ const HEARTBEAT_INTERVAL: u64 = 1;
 const ELECTION_TIMEOUT_MIN: u64 = 2 * HEARTBEAT_INTERVAL;
 const ELECTION_TIMEOUT_MAX: u64 = 5 * HEARTBEAT_INTERVAL;
 
+/// 节点角色, 日志里用到角色名
+pub trait Role {
+    const NAME: &'static str;
+
+    /// 这个角色下已知的leader, id是本节点id
+    fn leader<'a>(&'a self, _id: &'a str) -> Option<&'a str> {
+        None
+    }
+}
+
+impl Role for Follower {
+    const NAME: &'static str = "follower";
+
+    fn leader<'a>(&'a self, _id: &'a str) -> Option<&'a str> {
+        self.leader.as_deref()
+    }
+}
+
+impl Role for Candidate {
+    const NAME: &'static str = "candidate";
+}
+
+impl Role for Leader {
+    const NAME: &'static str = "leader";
+
+    fn leader<'a>(&'a self, id: &'a str) -> Option<&'a str> {
+        Some(id)
+    }
+}
+
+/// 节点状态, 供运维查询
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub id: String,
+    pub role: String,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub voted_for: Option<String>,
+    pub first_index: u64,
+    pub last_index: u64,
+    pub commit_index: u64,
+    pub applied_index: u64,
+    /// 只有leader有, 按peer id排序
+    pub peers: Vec<PeerStatus>,
+    pub queues: QueueDepths,
+}
+
+/// 各队列当前的长度
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueDepths {
+    pub inbound: u64,
+    pub outbound: u64,
+    /// 排队的和已提交还没回复的客户端请求
+    pub requests: u64,
+    /// peer id -> 等待发送的消息数
+    pub peers: BTreeMap<String, u64>,
+}
+
+/// leader视角下一个peer的复制进度
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub id: String,
+    pub next_index: u64,
+    pub match_index: u64,
+    /// 复制状态: probe, replicate, snapshot
+    pub state: String,
+    /// 已发出还没确认的复制消息数
+    pub inflight: u64,
+    /// 距离最后一次收到该peer消息的时间, 从没收到过为None
+    pub last_contact: Option<Duration>,
+    /// transport到该peer的连接是否正常
+    pub connected: bool,
+    pub snapshot_in_flight: bool,
+}
+
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Node {
     Candidate(RoleNode<Candidate>),
     Follower(RoleNode<Follower>),
@@ -27,67 +126,541 @@ pub enum Node {
 }
 
 impl Node {
-    pub async fn new(id: String, log: Log, peers: Vec<String>, tx: UnboundedSender<Message>) -> Result<Node> {
-        let (term, voted_for) = match log.get_metadata() {
-            Ok(v) => (v.0, v.1),
-            Err(_) => (0, None),
+    /// log和hard_state从存储恢复, applied_index是状态机已应用到的位置,
+    /// 之后已提交的log由第一个Ready交给调用方应用. forward_timeout是转发请求的超时, None时不转发
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        id: String,
+        log: Log,
+        hard_state: HardState,
+        peers: HashMap<String, String>,
+        applied_index: u64,
+        replication: ReplicationConfig,
+        forward_timeout: Option<Duration>,
+        metrics: Arc<Metrics>,
+        notifier: Arc<Notifier>,
+    ) -> Result<Node> {
+        let n = RoleNode {
+            id,
+            log,
+            applied_index,
+            applying_index: applied_index,
+            peers,
+            peers_down: HashSet::new(),
+            term: hard_state.term,
+            ready: Ready::default(),
+            replication,
+            forwards: Forwards::new(forward_timeout),
+            metrics,
+            notifier,
+            role: Follower::new(None, hard_state.voted_for),
         };
-        let n = RoleNode { id, log, peers, term, to_peer_tx: tx, role: Follower::new(None, voted_for) };
+        node_log!(info, n, "node started, last_index={} commit_index={} applied_index={}",
+            n.log.last_index, n.log.commit_index, n.applied_index);
+        n.observe();
         Ok(Node::Follower(n))
     }
 
-    pub fn tick(mut self) -> Result<Node> {
-        match self {
+    pub fn tick(self) -> Result<Node> {
+        let mut node = match self {
             Node::Follower(f) => f.tick(),
             Node::Leader(l) => l.tick(),
             Node::Candidate(c) => c.tick(),
-        }
+        }?;
+        //被退回的转发请求每个tick重试一次
+        node.forward()?;
+        node.observe();
+        Ok(node)
     }
 
     pub fn step(mut self, msg: Message) -> Result<Node> {
-        match self {
+        if let Event::PeerConnection { peer, connected } = &msg.event {
+            match &mut self {
+                Node::Follower(f) => f.peer_connection(peer, *connected),
+                Node::Leader(l) => l.peer_connection(peer, *connected),
+                Node::Candidate(c) => c.peer_connection(peer, *connected),
+            }
+        }
+        //转发请求的回复和任期无关, 旧leader下台时的回复也要转给客户端
+        if let Message { from: Address::Peer(from), event: Event::ClientResponse { id, response }, .. } = msg {
+            match &mut self {
+                Node::Follower(f) => f.forwarded(&from, id, response)?,
+                Node::Leader(l) => l.forwarded(&from, id, response)?,
+                Node::Candidate(c) => c.forwarded(&from, id, response)?,
+            }
+            return Ok(self);
+        }
+        let leader = self.leader().map(String::from);
+        let mut node = match self {
             Node::Follower(f) => f.step(msg),
             Node::Leader(l) => l.step(msg),
             Node::Candidate(c) => c.step(msg),
+        }?;
+        //换了leader马上转发; 被退回的等下个tick, 免得在旧leader上来回弹
+        if node.leader() != leader.as_deref() {
+            node.forward()?;
+        }
+        node.observe();
+        Ok(node)
+    }
+
+    /// 最早到期的转发请求的截止时间, 调用方到时调用expire_forwards
+    pub fn forward_deadline(&self) -> Option<Instant> {
+        match self {
+            Node::Follower(f) => f.forwards.deadline(),
+            Node::Leader(l) => l.forwards.deadline(),
+            Node::Candidate(c) => c.forwards.deadline(),
+        }
+    }
+
+    /// 让截止时间不晚于now的转发请求失败
+    pub fn expire_forwards(&mut self, now: Instant) -> Result<()> {
+        match self {
+            Node::Follower(f) => f.expire_forwards(now),
+            Node::Leader(l) => l.expire_forwards(now),
+            Node::Candidate(c) => c.expire_forwards(now),
+        }
+    }
+
+    /// 转发请求: 知道leader时发给它, 自己成了leader就自己处理
+    fn forward(&mut self) -> Result<()> {
+        match self {
+            Node::Follower(f) => f.send_forwards(),
+            Node::Leader(l) => l.serve_forwards(),
+            Node::Candidate(c) => c.send_forwards(),
+        }
+    }
+
+    /// 检查消息是否合法, step之前调用. 不合法的消息丢掉即可, 节点状态不变;
+    /// step本身返回的错误(如存储失败)则是致命的
+    pub fn validate(&self, msg: &Message) -> Result<()> {
+        let (log, peers) = match self {
+            Node::Follower(f) => (&f.log, &f.peers),
+            Node::Leader(l) => (&l.log, &l.peers),
+            Node::Candidate(c) => (&c.log, &c.peers),
+        };
+        let from = match &msg.from {
+            Address::Peer(from) => from,
+            //本地和客户端的消息只能是这几种
+            _ => return match msg.event {
+                Event::ClientRequest { .. } | Event::PeerConnection { .. } | Event::ReplicationDropped { .. } | Event::None =>
+                    Ok(()),
+                _ => Err(anyhow::anyhow!("unexpected {} from {:?}", msg.event.kind(), msg.from)),
+            },
+        };
+        if !peers.contains_key(from) {
+            return Err(anyhow::anyhow!("{} from unknown peer {}", msg.event.kind(), from));
+        }
+        match &msg.event {
+            Event::Heartbeat { commit_term, .. } if *commit_term > msg.term =>
+                Err(anyhow::anyhow!("heartbeat commit_term {} after term {}", commit_term, msg.term)),
+            Event::ReplicateEntries { base_index, base_term, entries } => {
+                if *base_term > msg.term {
+                    return Err(anyhow::anyhow!("base_term {} after term {}", base_term, msg.term));
+                }
+                let mut last_term = *base_term;
+                for (i, entry) in entries.iter().enumerate() {
+                    if entry.index != base_index + 1 + i as u64 {
+                        return Err(anyhow::anyhow!("entry {} out of order after base {}", entry.index, base_index));
+                    }
+                    if entry.term < last_term || entry.term > msg.term {
+                        return Err(anyhow::anyhow!("entry {} has invalid term {}", entry.index, entry.term));
+                    }
+                    last_term = entry.term;
+                    //已提交的log不能被覆盖
+                    if entry.index <= log.commit_index {
+                        if let Some(existing) = log.get(entry.index) {
+                            if existing.term != entry.term {
+                                return Err(anyhow::anyhow!("entry {} conflicts with committed log", entry.index));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Event::AcceptEntries { last_index } if *last_index > log.last_index =>
+                Err(anyhow::anyhow!("accepted index {} beyond last_index {}", last_index, log.last_index)),
+            Event::Heartbeat { .. }
+            | Event::SolicitVote { .. }
+            | Event::GrantVote
+            | Event::ConfirmLeader { .. }
+            | Event::AcceptEntries { .. }
+            | Event::RejectEntries { .. }
+            | Event::TimeoutNow
+            | Event::ClientResponse { .. } => Ok(()),
+            Event::ClientRequest { request, .. } if forward::forwardable(request) => Ok(()),
+            _ => Err(anyhow::anyhow!("unexpected {} from peer {}", msg.event.kind(), from)),
+        }
+    }
+
+    fn observe(&self) {
+        match self {
+            Node::Follower(f) => f.observe(),
+            Node::Leader(l) => l.observe(),
+            Node::Candidate(c) => c.observe(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Node::Follower(f) => &f.id,
+            Node::Leader(l) => &l.id,
+            Node::Candidate(c) => &c.id,
+        }
+    }
+
+    pub fn term(&self) -> u64 {
+        match self {
+            Node::Follower(f) => f.term,
+            Node::Leader(l) => l.term,
+            Node::Candidate(c) => c.term,
+        }
+    }
+
+    pub fn role_name(&self) -> &'static str {
+        match self {
+            Node::Follower(f) => f.role_name(),
+            Node::Leader(l) => l.role_name(),
+            Node::Candidate(c) => c.role_name(),
+        }
+    }
+
+    /// 是否有要调用方处理的Ready
+    pub fn has_ready(&self) -> bool {
+        match self {
+            Node::Follower(f) => f.has_ready(),
+            Node::Leader(l) => l.has_ready(),
+            Node::Candidate(c) => c.has_ready(),
+        }
+    }
+
+    /// 取走上次以来攒下的IO, 处理完之后调用advance
+    pub fn ready(&mut self) -> Result<Ready> {
+        Ok(match self {
+            Node::Follower(f) => f.ready(),
+            Node::Leader(l) => {
+                l.broadcast()?;
+                l.ready()
+            }
+            Node::Candidate(c) => c.ready(),
+        })
+    }
+
+    /// 确认一个Ready已经处理完: log已持久化, committed_entries已应用, reads已执行. Ready要按取出的顺序确认,
+    /// results是应用State命令的(index, 结果), 用来回复提交命令的客户端; reads是读请求的(id, 结果)
+    pub fn advance(&mut self, checkpoint: Checkpoint, results: Vec<(u64, Response)>, reads: Vec<(Vec<u8>, Response)>) -> Result<()> {
+        match self {
+            Node::Follower(f) => f.advance(checkpoint, reads)?,
+            Node::Leader(l) => {
+                let from = l.applied_index;
+                l.advance(checkpoint, reads)?;
+                //自己的写盘完成, 可能凑够了多数
+                l.commit()?;
+                l.applied(from, results)?;
+            }
+            Node::Candidate(c) => c.advance(checkpoint, reads)?,
+        }
+        self.observe();
+        Ok(())
+    }
+
+    /// 当前集群成员(不含自己), peer id -> 地址
+    pub fn peers(&self) -> &HashMap<String, String> {
+        match self {
+            Node::Follower(f) => &f.peers,
+            Node::Leader(l) => &l.peers,
+            Node::Candidate(c) => &c.peers,
+        }
+    }
+
+    /// 当前已知的leader, leader节点返回自己
+    pub fn leader(&self) -> Option<&str> {
+        match self {
+            Node::Follower(f) => f.leader(),
+            Node::Leader(l) => Some(&l.id),
+            Node::Candidate(_) => None,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let (log, applied_index, metrics) = match self {
+            Node::Follower(f) => (&f.log, f.applied_index, &f.metrics),
+            Node::Leader(l) => (&l.log, l.applied_index, &l.metrics),
+            Node::Candidate(c) => (&c.log, c.applied_index, &c.metrics),
+        };
+        let voted_for = match self {
+            Node::Follower(f) => f.voted_for().map(String::from),
+            //候选者和leader在本任期都投给了自己
+            Node::Leader(l) => Some(l.id.clone()),
+            Node::Candidate(c) => Some(c.id.clone()),
+        };
+        let mut peers = vec![];
+        if let Node::Leader(l) = self {
+            for (peer, pr) in l.role.progress.iter() {
+                peers.push(PeerStatus {
+                    id: peer.clone(),
+                    next_index: pr.next_index,
+                    match_index: pr.match_index,
+                    state: pr.state.name().to_string(),
+                    inflight: pr.inflight() as u64,
+                    last_contact: l.role.peer_last_contact.get(peer).map(|t| t.elapsed()),
+                    connected: !l.peers_down.contains(peer),
+                    snapshot_in_flight: pr.state == ProgressState::Snapshot,
+                });
+            }
+            peers.sort_by(|a, b| a.id.cmp(&b.id));
+        }
+        Status {
+            id: self.id().to_string(),
+            role: self.role_name().to_string(),
+            term: self.term(),
+            leader: self.leader().map(String::from),
+            voted_for,
+            first_index: log.first_index(),
+            last_index: log.last_index,
+            commit_index: log.commit_index,
+            applied_index,
+            peers,
+            queues: QueueDepths {
+                inbound: metrics.inbound_queue.get(),
+                outbound: metrics.outbound_queue.get(),
+                requests: metrics.request_queue.get(),
+                peers: self.peers().keys().map(|p| (p.clone(), metrics.peer_queue.get(&[p]))).collect(),
+            },
+        }
+    }
+
+    /// 把节点当前状态写到指标里, 每次tick/step之后调用
+    pub fn report_metrics(&self, metrics: &Metrics) {
+        let (log, applied_index) = match self {
+            Node::Follower(f) => (&f.log, f.applied_index),
+            Node::Leader(l) => (&l.log, l.applied_index),
+            Node::Candidate(c) => (&c.log, c.applied_index),
+        };
+        metrics.term.set(self.term());
+        metrics.role.clear();
+        metrics.role.set(&[self.role_name()], 1);
+        metrics.leader.clear();
+        if let Some(leader) = self.leader() {
+            metrics.leader.set(&[leader], 1);
+        }
+        metrics.commit_index.set(log.commit_index);
+        metrics.applied_index.set(applied_index);
+        metrics.last_index.set(log.last_index);
+        let (peers, peers_down) = match self {
+            Node::Follower(f) => (&f.peers, &f.peers_down),
+            Node::Leader(l) => (&l.peers, &l.peers_down),
+            Node::Candidate(c) => (&c.peers, &c.peers_down),
+        };
+        metrics.peer_connected.clear();
+        for peer in peers.keys() {
+            metrics.peer_connected.set(&[peer], !peers_down.contains(peer) as u64);
+        }
+        metrics.peer_match_index.clear();
+        metrics.peer_lag.clear();
+        if let Node::Leader(l) = self {
+            for (peer, pr) in l.role.progress.iter() {
+                metrics.peer_match_index.set(&[peer], pr.match_index);
+                metrics.peer_lag.set(&[peer], log.last_index.saturating_sub(pr.match_index));
+            }
         }
     }
 }
 
 #[derive(Debug)]
-pub struct RoleNode<Role> {
+pub struct RoleNode<R> {
     id: String,
     log: Log,
-    peers: Vec<String>,
+    //已应用到状态机的index
+    applied_index: u64,
+    //已经交给调用方应用的index
+    applying_index: u64,
+    peers: HashMap<String, String>,
+    //transport报告连接断开的peer, 恢复前leader暂停给它复制log
+    peers_down: HashSet<String>,
     term: u64,
-    to_peer_tx: UnboundedSender<Message>,
-    role: Role,
+    //还没交给调用方的IO
+    ready: Ready,
+    replication: ReplicationConfig,
+    //转发给leader的请求和leader上收到的转发请求
+    forwards: Forwards,
+    metrics: Arc<Metrics>,
+    notifier: Arc<Notifier>,
+    role: R,
 }
 
-impl<Role> RoleNode<Role> {
-    pub fn transfer_role<R>(self, r: R) -> Result<RoleNode<R>> {
-        Ok(RoleNode {
+impl<R: Role> RoleNode<R> {
+    pub fn transfer_role<T: Role>(self, r: T) -> Result<RoleNode<T>> {
+        //转换前的任期/leader/commit变化先通知, 保证订阅者看到的顺序和发生顺序一致
+        self.observe();
+        let node = RoleNode {
             id: self.id,
             log: self.log,
+            applied_index: self.applied_index,
+            applying_index: self.applying_index,
             peers: self.peers,
+            peers_down: self.peers_down,
             term: self.term,
-            to_peer_tx: self.to_peer_tx,
+            ready: self.ready,
+            replication: self.replication,
+            forwards: self.forwards,
+            metrics: self.metrics,
+            notifier: self.notifier,
             role: r,
-        })
+        };
+        node_log!(info, node, "role changed from {}", R::NAME);
+        node.notifier.role(node.term, R::NAME, T::NAME);
+        Ok(node)
+    }
+
+    fn peer_connection(&mut self, peer: &str, connected: bool) {
+        if connected {
+            node_log!(info, self, "peer {} connected", peer);
+            self.peers_down.remove(peer);
+        } else {
+            node_log!(warn, self, "peer {} disconnected", peer);
+            self.peers_down.insert(peer.to_string());
+        }
     }
 
-    pub fn send(&self, to: Address, event: Event) -> Result<()> {
+    /// 把任期/leader/commit index的变化通知给订阅者
+    fn observe(&self) {
+        self.notifier.term(self.term);
+        self.notifier.leader(self.term, self.role.leader(&self.id));
+        self.notifier.commit(self.term, self.log.commit_index);
+    }
+
+    pub fn send(&mut self, to: Address, event: Event) -> Result<()> {
         let msg = Message {
             term: self.term,
             from: Address::Local,
             to,
             event,
         };
-        self.to_peer_tx.unbounded_send(msg);
+        node_log!(trace, self, "send {:?}", msg);
+        self.ready.messages.push(msg);
         Ok(())
     }
 
+    pub fn role_name(&self) -> &'static str {
+        R::NAME
+    }
+
+    /// 回复客户端, peer转发来的请求回复给peer
+    pub fn respond(&mut self, id: Vec<u8>, response: Response) -> Result<()> {
+        if let Some((peer, id)) = self.forwards.reply_to(&id) {
+            return self.send(Address::Peer(peer), Event::ClientResponse { id, response });
+        }
+        self.send(Address::Client, Event::ClientResponse { id, response })
+    }
+
+    /// 不是leader时拒绝客户端请求, 告诉它知道的leader
+    pub fn reject_client(&mut self, id: Vec<u8>, leader: Option<String>) -> Result<()> {
+        self.metrics.proposals_rejected.inc();
+        self.respond(id, Err(RequestError::NotLeader { leader }))
+    }
+
+    /// 进入新任期或投票后保存
+    fn save_hard_state(&mut self, voted_for: Option<String>) {
+        self.ready.hard_state = Some(HardState { term: self.term, voted_for });
+    }
+
+    /// 提交到index, 其中的成员变更马上生效
+    fn commit_to(&mut self, index: u64) -> Result<()> {
+        let from = self.log.commit_index + 1;
+        self.log.commit(index)?;
+        let changes: Vec<ConfigChange> = self.log.scan(from..=index)
+            .filter_map(|e| match &e.command {
+                Command::Membership(change) => Some(change.clone()),
+                _ => None,
+            })
+            .collect();
+        for change in changes {
+            self.change_membership(change)?;
+        }
+        Ok(())
+    }
+
+    fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+            || self.log.truncated.is_some()
+            || self.log.unstable_index < self.log.last_index
+            || self.log.unstable_commit < self.log.commit_index
+            || self.applying_index < self.log.commit_index
+    }
+
+    fn ready(&mut self) -> Ready {
+        let mut ready = std::mem::take(&mut self.ready);
+        ready.truncate = self.log.truncated.take();
+        ready.entries = self.log.take_unstable();
+        if self.log.unstable_commit < self.log.commit_index {
+            self.log.unstable_commit = self.log.commit_index;
+            ready.commit_index = Some(self.log.commit_index);
+        }
+        ready.committed_entries = self.log.scan(self.applying_index + 1..=self.log.commit_index).cloned().collect();
+        self.applying_index = self.log.commit_index;
+        ready.checkpoint = Checkpoint {
+            stable_index: self.log.unstable_index,
+            stable_term: self.log.get(self.log.unstable_index).map_or(0, |e| e.term),
+            applied_index: self.applying_index,
+        };
+        ready
+    }
+
+    fn advance(&mut self, checkpoint: Checkpoint, reads: Vec<(Vec<u8>, Response)>) -> Result<()> {
+        //写入期间log可能被截断过, 同一位置任期相同时前面的log也相同(log匹配特性), 才算持久化了
+        if self.log.has(checkpoint.stable_index, checkpoint.stable_term) {
+            self.log.stable_to(checkpoint.stable_index.max(self.log.stable_index));
+        }
+        self.applied_index = self.applied_index.max(checkpoint.applied_index);
+        //读请求在leader确认身份时就已经成立, 之后角色变了也照样回复
+        for (id, response) in reads {
+            self.respond(id, response)?;
+        }
+        Ok(())
+    }
+
+    fn change_membership(&mut self, change: ConfigChange) -> Result<()> {
+        node_log!(info, self, "membership change {:?}", change);
+        match change {
+            ConfigChange::AddPeer { id, addr } if id != self.id => { self.peers.insert(id, addr); }
+            ConfigChange::RemovePeer { id } => { self.peers.remove(&id); }
+            _ => (),
+        }
+        self.ready.peers = Some(self.peers.clone());
+        self.send(Address::Local, Event::PeersChanged { peers: self.peers.clone() })
+    }
+
     ///超过这个数的人赞成, 恭喜你,你就当选了
     pub fn watershed(&self) -> u64 {
-        (self.peers.len() as u64 + 1) / 2 + 1
+        (self.peers.len() as u64).div_ceil(2) + 1
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 空log的新节点, peers是其他节点的id
+    pub fn node(id: &str, peers: &[&str]) -> Node {
+        let peers = peers.iter().map(|p| (p.to_string(), format!("{}:1", p))).collect();
+        let log = Log::new(vec![], 0).unwrap();
+        crate::runtime::block_on(Node::new(
+            id.to_string(), log, HardState::default(), peers, 0, ReplicationConfig::default(), None,
+            Arc::new(Metrics::new()), Arc::new(Notifier::new()),
+        )).unwrap()
+    }
+
+    /// tick到选举超时, 成为候选者
+    pub fn candidate(id: &str, peers: &[&str]) -> Node {
+        let mut node = node(id, peers);
+        while node.role_name() != "candidate" {
+            node = node.tick().unwrap();
+        }
+        node
+    }
+
+    pub fn message(term: u64, from: &str, event: Event) -> Message {
+        Message { term, from: Address::Peer(from.to_string()), to: Address::Peers, event }
     }
 }
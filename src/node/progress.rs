@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+
+use crate::conf::ReplicationConfig;
+
+/// leader给一个peer复制log的状态, 和etcd的一样
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressState {
+    /// 不知道peer的log和自己在哪里一致, 一次只发一条消息, 被拒绝就往前找
+    Probe,
+    /// log已经对上, 不等回应连续发送, 在途的消息受窗口限制
+    Replicate,
+    /// peer需要的log已经被压缩掉, 等它装好快照
+    Snapshot,
+}
+
+impl ProgressState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressState::Probe => "probe",
+            ProgressState::Replicate => "replicate",
+            ProgressState::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// leader视角下一个peer的复制进度
+#[derive(Debug)]
+pub struct Progress {
+    pub state: ProgressState,
+    //下一条要发的log index
+    pub next_index: u64,
+    //已知peer上已复制的最后index
+    pub match_index: u64,
+    //probe状态下发出的消息还没回应, 收到回应或心跳回应之前不再发
+    probe_sent: bool,
+    //snapshot状态下发出的快照的index
+    pub pending_snapshot: u64,
+    inflights: Inflights,
+}
+
+/// 已经发出还没确认的消息, 每条记下它最后一条log的index和字节数
+#[derive(Debug)]
+struct Inflights {
+    messages: VecDeque<(u64, usize)>,
+    bytes: usize,
+    max_messages: usize,
+    max_bytes: usize,
+}
+
+impl Inflights {
+    fn full(&self) -> bool {
+        self.messages.len() >= self.max_messages || self.bytes >= self.max_bytes
+    }
+
+    fn add(&mut self, last_index: u64, bytes: usize) {
+        self.messages.push_back((last_index, bytes));
+        self.bytes += bytes;
+    }
+
+    /// index及之前的消息都确认了
+    fn free_to(&mut self, index: u64) {
+        while let Some(&(last_index, bytes)) = self.messages.front() {
+            if last_index > index {
+                break;
+            }
+            self.messages.pop_front();
+            self.bytes -= bytes;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.messages.clear();
+        self.bytes = 0;
+    }
+}
+
+impl Progress {
+    /// 新leader或新peer从probe开始, 从next_index往前找
+    pub fn new(next_index: u64, conf: &ReplicationConfig) -> Progress {
+        Progress {
+            state: ProgressState::Probe,
+            next_index,
+            match_index: 0,
+            probe_sent: false,
+            pending_snapshot: 0,
+            inflights: Inflights {
+                messages: VecDeque::new(),
+                bytes: 0,
+                max_messages: conf.max_inflight_msgs.max(1),
+                max_bytes: conf.max_inflight_bytes.max(1),
+            },
+        }
+    }
+
+    pub fn become_probe(&mut self) {
+        //装完快照从快照之后开始找
+        self.next_index = match self.state {
+            ProgressState::Snapshot => self.match_index.max(self.pending_snapshot) + 1,
+            _ => self.match_index + 1,
+        };
+        self.state = ProgressState::Probe;
+        self.probe_sent = false;
+        self.pending_snapshot = 0;
+        self.inflights.reset();
+    }
+
+    pub fn become_replicate(&mut self) {
+        self.state = ProgressState::Replicate;
+        self.next_index = self.match_index + 1;
+        self.probe_sent = false;
+        self.inflights.reset();
+    }
+
+    pub fn become_snapshot(&mut self, index: u64) {
+        self.state = ProgressState::Snapshot;
+        self.pending_snapshot = index;
+        self.inflights.reset();
+    }
+
+    /// 现在不能再给这个peer发log
+    pub fn is_paused(&self) -> bool {
+        match self.state {
+            ProgressState::Probe => self.probe_sent,
+            ProgressState::Replicate => self.inflights.full(),
+            ProgressState::Snapshot => true,
+        }
+    }
+
+    /// 发出了一条最后一条log为last_index, 带bytes字节log的消息
+    pub fn sent(&mut self, last_index: u64, bytes: usize) {
+        match self.state {
+            ProgressState::Probe => self.probe_sent = true,
+            ProgressState::Replicate => {
+                self.next_index = last_index + 1;
+                self.inflights.add(last_index, bytes);
+            }
+            ProgressState::Snapshot => (),
+        }
+    }
+
+    /// peer确认有了last_index及之前的log, 过期的确认返回false
+    pub fn accepted(&mut self, last_index: u64) -> bool {
+        if last_index <= self.match_index && self.state != ProgressState::Probe {
+            return false;
+        }
+        self.match_index = self.match_index.max(last_index);
+        self.next_index = self.next_index.max(last_index + 1);
+        self.inflights.free_to(last_index);
+        match self.state {
+            ProgressState::Probe => self.become_replicate(),
+            ProgressState::Snapshot if self.match_index >= self.pending_snapshot => self.become_probe(),
+            _ => (),
+        }
+        true
+    }
+
+    /// peer在base_index处对不上, 它的log到last_index. 过期的拒绝返回false
+    pub fn rejected(&mut self, base_index: u64, last_index: u64) -> bool {
+        match self.state {
+            //在途的消息都作废, 从已确认的位置重新找
+            ProgressState::Replicate if base_index > self.match_index => {
+                self.become_probe();
+                true
+            }
+            //只有对最近一次探测的拒绝才有用, peer的log比base短时直接跳到它的末尾
+            ProgressState::Probe if base_index + 1 == self.next_index => {
+                self.next_index = base_index.min(last_index + 1).max(self.match_index + 1);
+                self.probe_sent = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// peer回应了心跳, probe状态下可以再探测一次
+    pub fn heartbeat_response(&mut self) {
+        self.probe_sent = false;
+    }
+
+    /// 在途的消息数
+    pub fn inflight(&self) -> usize {
+        self.inflights.messages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(next_index: u64, max_inflight_msgs: usize, max_inflight_bytes: usize) -> Progress {
+        let conf = ReplicationConfig { max_inflight_msgs, max_inflight_bytes, ..ReplicationConfig::default() };
+        Progress::new(next_index, &conf)
+    }
+
+    #[test]
+    fn probe_then_replicate() {
+        let mut pr = progress(11, 4, 1 << 20);
+        assert_eq!(pr.state, ProgressState::Probe);
+        //probe一次只发一条, 收到回应前暂停
+        pr.sent(10, 100);
+        assert!(pr.is_paused());
+        assert_eq!(pr.next_index, 11);
+        //心跳回应后可以再探测
+        pr.heartbeat_response();
+        assert!(!pr.is_paused());
+
+        assert!(pr.accepted(10));
+        assert_eq!(pr.state, ProgressState::Replicate);
+        assert_eq!((pr.match_index, pr.next_index), (10, 11));
+        //replicate不等回应连续发
+        pr.sent(12, 100);
+        pr.sent(14, 100);
+        assert_eq!((pr.next_index, pr.inflight()), (15, 2));
+        assert!(pr.accepted(12));
+        assert_eq!((pr.match_index, pr.inflight()), (12, 1));
+        //过期的确认不算
+        assert!(!pr.accepted(11));
+        assert_eq!(pr.match_index, 12);
+    }
+
+    #[test]
+    fn probe_rejection_moves_back() {
+        let mut pr = progress(11, 4, 1 << 20);
+        pr.sent(10, 0);
+        //peer的log只到5, 直接跳到它的末尾
+        assert!(pr.rejected(10, 5));
+        assert_eq!(pr.next_index, 6);
+        assert!(!pr.is_paused());
+        //不是对最近一次探测的拒绝, 忽略
+        pr.sent(10, 0);
+        assert!(!pr.rejected(10, 3));
+        assert_eq!(pr.next_index, 6);
+        //peer的log更长但base对不上, 往前退一条
+        assert!(pr.rejected(5, 8));
+        assert_eq!(pr.next_index, 5);
+    }
+
+    #[test]
+    fn replicate_rejection_falls_back_to_probe() {
+        let mut pr = progress(1, 4, 1 << 20);
+        assert!(pr.accepted(3));
+        pr.sent(5, 100);
+        pr.sent(7, 100);
+        //已确认位置之前的拒绝是过期的
+        assert!(!pr.rejected(2, 9));
+        assert_eq!(pr.state, ProgressState::Replicate);
+        //在途的消息作废, 从已确认的位置重新找
+        assert!(pr.rejected(5, 4));
+        assert_eq!(pr.state, ProgressState::Probe);
+        assert_eq!((pr.next_index, pr.inflight()), (4, 0));
+    }
+
+    #[test]
+    fn snapshot_then_probe() {
+        let mut pr = progress(1, 4, 1 << 20);
+        assert!(pr.accepted(3));
+        pr.sent(5, 100);
+        //需要的log已经压缩了, 等快照装好之前不发log
+        pr.become_snapshot(20);
+        assert_eq!((pr.state, pr.inflight()), (ProgressState::Snapshot, 0));
+        assert!(pr.is_paused());
+        pr.sent(6, 100);
+        assert_eq!(pr.inflight(), 0);
+        //还没到快照的确认不结束snapshot状态
+        assert!(pr.accepted(10));
+        assert_eq!(pr.state, ProgressState::Snapshot);
+        //装好快照后从快照之后开始探测
+        assert!(pr.accepted(20));
+        assert_eq!((pr.state, pr.next_index), (ProgressState::Probe, 21));
+        assert!(!pr.is_paused());
+    }
+
+    #[test]
+    fn inflight_limits() {
+        //条数到上限后暂停, 确认后继续
+        let mut pr = progress(1, 2, 1 << 20);
+        assert!(pr.accepted(0));
+        pr.sent(1, 10);
+        assert!(!pr.is_paused());
+        pr.sent(2, 10);
+        assert!(pr.is_paused());
+        assert!(pr.accepted(1));
+        assert!(!pr.is_paused());
+
+        //字节数到上限后暂停
+        let mut pr = progress(1, 100, 1000);
+        assert!(pr.accepted(0));
+        pr.sent(1, 600);
+        assert!(!pr.is_paused());
+        pr.sent(2, 400);
+        assert!(pr.is_paused());
+        assert!(pr.accepted(2));
+        assert_eq!(pr.inflight(), 0);
+        assert!(!pr.is_paused());
+    }
+}
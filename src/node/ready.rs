@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::log::log::{Entry, LogWrite};
+use crate::message::Message;
+
+/// 要持久化的任期和投票, 重启后不能在同一任期再投给别人
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+/// 可以执行的读请求: leader身份已经确认, 状态机也已应用到收到请求时的commit index
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadState {
+    pub id: Vec<u8>,
+    pub query: Vec<u8>,
+}
+
+/// 状态机快照
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+/// Ready处理完后交给Node::advance: 交出去的log持久化到了哪里, 状态机应用到了哪里
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    pub stable_index: u64,
+    pub stable_term: u64,
+    pub applied_index: u64,
+}
+
+/// 节点在tick/step里只改内存状态, 要做的IO攒在Ready里, 由调用方用Node::ready取走后按顺序执行:
+///  1, 持久化truncate/entries/hard_state/peers/commit_index, 同时就可以发送replicate_messages
+///  2, 持久化完成后发送messages
+///  3, 安装snapshot, 按顺序应用committed_entries, 再执行reads
+/// 都做完后用checkpoint和应用/读的结果调用Node::advance确认. 持久化可以异步进行, 多个Ready按顺序完成即可
+#[derive(Debug, Default)]
+pub struct Ready {
+    pub hard_state: Option<HardState>,
+    /// 成员变更后的集群成员
+    pub peers: Option<HashMap<String, String>>,
+    /// 删除这个index之后(不含)的log, 在追加entries之前执行
+    pub truncate: Option<u64>,
+    /// 要追加到存储的log
+    pub entries: Vec<Entry>,
+    pub commit_index: Option<u64>,
+    pub messages: Vec<Message>,
+    /// leader复制log和心跳的消息, 不用等本地写完就可以发送(论文10.2.1)
+    pub replicate_messages: Vec<Message>,
+    /// 要安装到状态机的快照, 还没有实现快照, 目前总是None
+    pub snapshot: Option<Snapshot>,
+    /// 已提交, 要应用到状态机的log
+    pub committed_entries: Vec<Entry>,
+    pub reads: Vec<ReadState>,
+    pub checkpoint: Checkpoint,
+}
+
+impl Ready {
+    /// 取出要持久化的部分, 没有要写的时候为None
+    pub fn take_write(&mut self) -> Option<LogWrite> {
+        let write = LogWrite {
+            truncate: self.truncate.take(),
+            entries: std::mem::take(&mut self.entries),
+            hard_state: self.hard_state.take(),
+            peers: self.peers.take(),
+            commit_index: self.commit_index.take(),
+        };
+        if write.is_empty() { None } else { Some(write) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hard_state.is_none()
+            && self.peers.is_none()
+            && self.truncate.is_none()
+            && self.entries.is_empty()
+            && self.commit_index.is_none()
+            && self.messages.is_empty()
+            && self.replicate_messages.is_empty()
+            && self.snapshot.is_none()
+            && self.committed_entries.is_empty()
+            && self.reads.is_empty()
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// 节点上发生的事件
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeEvent {
+    /// 角色转换, 如follower -> candidate
+    RoleChanged { from: &'static str, to: &'static str },
+    TermChanged { term: u64 },
+    /// 已知的leader变了, None表示当前不知道leader是谁
+    LeaderChanged { leader: Option<String> },
+    CommitAdvanced { commit_index: u64 },
+    /// leader在一个选举超时内没有收到多数节点的消息
+    QuorumLost,
+    QuorumRestored,
+}
+
+/// 推给订阅者的事件, 带发生时间和当时的任期
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub time: SystemTime,
+    pub term: u64,
+    pub event: NodeEvent,
+}
+
+/// 订阅者收到事件的通道, 按发生顺序, 不会丢
+pub type Subscription = UnboundedReceiver<Notification>;
+
+#[derive(Debug)]
+struct Inner {
+    subscribers: Vec<UnboundedSender<Notification>>,
+    //上次通知过的值, 没变化时不重复通知
+    term: u64,
+    leader: Option<String>,
+    commit_index: u64,
+    quorum: bool,
+}
+
+/// 把节点事件分发给所有订阅者. 通道无界, 发送不会阻塞event loop
+#[derive(Debug)]
+pub struct Notifier(Mutex<Inner>);
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier(Mutex::new(Inner {
+            subscribers: vec![],
+            term: 0,
+            leader: None,
+            commit_index: 0,
+            quorum: true,
+        }))
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, rx) = mpsc::unbounded();
+        self.0.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    pub fn role(&self, term: u64, from: &'static str, to: &'static str) {
+        let mut inner = self.0.lock().unwrap();
+        inner.publish(term, NodeEvent::RoleChanged { from, to });
+    }
+
+    pub fn term(&self, term: u64) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.term != term {
+            inner.term = term;
+            inner.publish(term, NodeEvent::TermChanged { term });
+        }
+    }
+
+    pub fn leader(&self, term: u64, leader: Option<&str>) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.leader.as_deref() != leader {
+            inner.leader = leader.map(String::from);
+            inner.publish(term, NodeEvent::LeaderChanged { leader: leader.map(String::from) });
+        }
+    }
+
+    pub fn commit(&self, term: u64, commit_index: u64) {
+        let mut inner = self.0.lock().unwrap();
+        if commit_index > inner.commit_index {
+            inner.commit_index = commit_index;
+            inner.publish(term, NodeEvent::CommitAdvanced { commit_index });
+        }
+    }
+
+    pub fn quorum(&self, term: u64, quorum: bool) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.quorum != quorum {
+            inner.quorum = quorum;
+            let event = if quorum { NodeEvent::QuorumRestored } else { NodeEvent::QuorumLost };
+            inner.publish(term, event);
+        }
+    }
+
+    /// 新当选的leader默认有多数派, 不通知
+    pub fn reset_quorum(&self) {
+        self.0.lock().unwrap().quorum = true;
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new()
+    }
+}
+
+impl Inner {
+    fn publish(&mut self, term: u64, event: NodeEvent) {
+        let n = Notification { time: SystemTime::now(), term, event };
+        //订阅者丢掉了接收端就不再发给它
+        self.subscribers.retain(|tx| tx.unbounded_send(n.clone()).is_ok());
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::{FutureExt, SinkExt, StreamExt};
+use rand::Rng;
+
+use crate::logging::LogContext;
+use crate::message::{Address, Event, Message, RequestError};
+use crate::metrics::Metrics;
+use crate::runtime;
+use crate::tls::Stream;
+use crate::transport::{encode_frame, write_frames, Endpoint};
+
+/// 重连间隔从MIN开始每次翻倍, 最多MAX
+const BACKOFF_MIN: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// 一次写入最多合并的消息数和字节数
+const BATCH_MESSAGES: usize = 64;
+const BATCH_BYTES: usize = 1 << 20;
+
+/// 发给一个peer的消息队列, 有界. 满了只丢leader会重新生成的消息, 投票和请求的回复超过上限也不丢
+#[derive(Debug)]
+struct Queue {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    //丢过日志复制还没有报告给节点
+    appends_dropped: bool,
+}
+
+impl Queue {
+    fn new(capacity: usize) -> Queue {
+        Queue { messages: VecDeque::new(), capacity, appends_dropped: false }
+    }
+
+    /// 放入消息, 返回为了腾位置丢掉的消息. 满了又没有可丢的消息时转发的请求放不进去, 原样返回
+    fn push(&mut self, msg: Message) -> Option<Message> {
+        //心跳只有最新的有意义
+        if is_heartbeat(&msg) {
+            self.messages.retain(|m| !is_heartbeat(m));
+        }
+        if self.messages.len() < self.capacity {
+            self.messages.push_back(msg);
+            return None;
+        }
+        //满了丢最老的可丢消息, leader会按peer的进度重发
+        if let Some(i) = self.messages.iter().position(droppable) {
+            let dropped = self.messages.remove(i);
+            self.messages.push_back(msg);
+            self.dropped(dropped.as_ref());
+            return dropped;
+        }
+        if droppable(&msg) || matches!(msg.event, Event::ClientRequest { .. }) {
+            self.dropped(Some(&msg));
+            return Some(msg);
+        }
+        //投票和回复超过上限也放入
+        self.messages.push_back(msg);
+        None
+    }
+
+    /// 超过上限时丢掉可丢的消息, 最老的先丢, 返回丢掉的个数
+    fn trim(&mut self) -> usize {
+        let mut dropped = 0;
+        while self.messages.len() > self.capacity {
+            match self.messages.iter().position(droppable) {
+                Some(i) => {
+                    let msg = self.messages.remove(i);
+                    self.dropped(msg.as_ref());
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    fn dropped(&mut self, msg: Option<&Message>) {
+        if msg.is_some_and(is_append) {
+            self.appends_dropped = true;
+        }
+    }
+
+    /// 断线期间积压的心跳已经过期, 重连后由leader发新的
+    fn drop_stale(&mut self) {
+        self.messages.retain(|m| !is_heartbeat(m));
+    }
+}
+
+/// 往一个peer的发送队列里放消息, 不会阻塞. 丢掉后发送任务发完队列就结束
+#[derive(Debug)]
+pub(crate) struct PeerQueue {
+    ctx: Arc<LogContext>,
+    peer: String,
+    queue: Arc<Mutex<Queue>>,
+    //队列从空变为非空时叫醒发送任务
+    wake: mpsc::Sender<()>,
+    //放不下的转发请求以Overloaded回复退回给节点
+    node_tx: mpsc::Sender<Message>,
+    metrics: Arc<Metrics>,
+}
+
+impl PeerQueue {
+    pub(crate) fn push(&mut self, msg: Message) {
+        let mut queue = self.queue.lock().unwrap();
+        let pushed = queue.push(msg);
+        self.metrics.peer_queue.set(&[&self.peer], queue.messages.len() as u64);
+        drop(queue);
+        match pushed {
+            Some(msg) if matches!(msg.event, Event::ClientRequest { .. }) => self.bounce(msg),
+            Some(dropped) =>
+                log::debug!("[{}] queue to peer {} is full, dropped {}", self.ctx, self.peer, dropped.event.kind()),
+            None => (),
+        }
+        let _ = self.wake.try_send(());
+    }
+
+    /// 转发请求没有发出去, 当作peer回复了Overloaded. 节点的消息队列也满了时只能等转发超时
+    fn bounce(&mut self, msg: Message) {
+        let id = match msg.event {
+            Event::ClientRequest { id, .. } => id,
+            _ => return,
+        };
+        log::warn!("[{}] queue to peer {} is full, request {:?} bounced", self.ctx, self.peer, id);
+        let response = Message {
+            term: 0,
+            from: Address::Peer(self.peer.clone()),
+            to: Address::Local,
+            event: Event::ClientResponse { id, response: Err(RequestError::Overloaded) },
+        };
+        if self.node_tx.try_send(response).is_ok() {
+            self.metrics.inbound_queue.inc();
+        }
+    }
+}
+
+
+fn is_heartbeat(msg: &Message) -> bool {
+    matches!(msg.event, Event::Heartbeat { .. })
+}
+
+fn is_append(msg: &Message) -> bool {
+    matches!(msg.event, Event::ReplicateEntries { .. })
+}
+
+/// leader会重新生成的消息: 心跳和它的回应, 日志复制
+fn droppable(msg: &Message) -> bool {
+    matches!(msg.event, Event::Heartbeat { .. } | Event::ConfirmLeader { .. } | Event::ReplicateEntries { .. })
+}
+
+/// 到一个peer的发送任务: 断线后按指数退避加随机抖动重连, 写失败的消息留在队列里重连后再发.
+/// 连接断开和恢复, 丢掉了日志复制, 都通过node_tx报告给节点
+pub(crate) struct PeerSender {
+    ctx: Arc<LogContext>,
+    peer: String,
+    addr: String,
+    queue: Arc<Mutex<Queue>>,
+    wake: mpsc::Receiver<()>,
+    node_tx: mpsc::Sender<Message>,
+    metrics: Arc<Metrics>,
+    //建立连接和握手的方式
+    endpoint: Endpoint,
+    //节点一开始认为peer是连着的
+    connected: bool,
+}
+
+impl PeerSender {
+    /// 发送任务和往它队列里放消息的一端, capacity是队列上限
+    pub(crate) fn new(
+        ctx: Arc<LogContext>,
+        peer: String,
+        addr: String,
+        capacity: usize,
+        endpoint: Endpoint,
+        node_tx: mpsc::Sender<Message>,
+        metrics: Arc<Metrics>,
+    ) -> (PeerSender, PeerQueue) {
+        let queue = Arc::new(Mutex::new(Queue::new(capacity)));
+        let (wake_tx, wake_rx) = mpsc::channel(1);
+        let sender = PeerSender {
+            ctx: ctx.clone(),
+            peer: peer.clone(),
+            addr,
+            queue: queue.clone(),
+            wake: wake_rx,
+            node_tx: node_tx.clone(),
+            metrics: metrics.clone(),
+            endpoint,
+            connected: true,
+        };
+        (sender, PeerQueue { ctx, peer, queue, wake: wake_tx, node_tx, metrics })
+    }
+
+    pub(crate) async fn run(mut self) -> Result<()> {
+        let r = self.send_loop().await;
+        self.metrics.peer_queue.remove(&[&self.peer]);
+        r
+    }
+
+    async fn send_loop(&mut self) -> Result<()> {
+        let mut backoff = BACKOFF_MIN;
+        loop {
+            match self.connect().await {
+                Ok(mut socket) => {
+                    log::info!("[{}] connected to peer {} at {}", self.ctx, self.peer, self.addr);
+                    backoff = BACKOFF_MIN;
+                    if !self.connected {
+                        self.queue.lock().unwrap().drop_stale();
+                        self.report(true).await;
+                    }
+                    match self.send_queued(&mut socket).await {
+                        Ok(()) => return Ok(()),
+                        Err(e) => log::warn!("[{}] connection to peer {} lost: {}", self.ctx, self.peer, e),
+                    }
+                }
+                Err(e) if self.connected =>
+                    log::warn!("[{}] connect to peer {} at {} failed: {}", self.ctx, self.peer, self.addr, e),
+                Err(e) => log::debug!("[{}] connect to peer {} at {} failed: {}", self.ctx, self.peer, self.addr, e),
+            }
+            if self.connected {
+                self.report(false).await;
+            }
+
+            let delay = rand::thread_rng().gen_range(backoff / 2..=backoff);
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+            log::debug!("[{}] reconnect to peer {} in {:?}", self.ctx, self.peer, delay);
+            //等待重连期间消息继续进队列; 停止中的节点不再重连
+            let mut sleep = Box::pin(runtime::sleep(delay).fuse());
+            loop {
+                futures::select! {
+                    _ = sleep => break,
+                    wake = self.wake.next() => match wake {
+                        Some(()) => self.report_dropped().await,
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+    }
+
+    /// 建立连接并握手, 确认连上的是同一集群里的这个peer
+    async fn connect(&self) -> Result<Stream> {
+        let socket = runtime::timeout(CONNECT_TIMEOUT, runtime::connect(&self.addr)).await??;
+        match self.endpoint.connect(socket, &self.addr, Some(&self.peer)).await {
+            Ok((socket, _)) => Ok(socket),
+            Err(e) => {
+                self.metrics.handshake_failures.inc();
+                Err(e)
+            }
+        }
+    }
+
+    /// 按顺序发送队列里的消息, 已经排队的合并成一次写, 写失败的放回队头
+    async fn send_queued(&mut self, socket: &mut Stream) -> Result<()> {
+        loop {
+            self.report_dropped().await;
+            let mut batch = vec![];
+            let mut frames = vec![];
+            let mut bytes = 0;
+            while batch.len() < BATCH_MESSAGES && bytes < BATCH_BYTES {
+                let msg = self.queue.lock().unwrap().messages.pop_front();
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                match encode_frame(&msg) {
+                    Ok(frame) => {
+                        bytes += frame.len();
+                        frames.push(frame);
+                        batch.push(msg);
+                    }
+                    //重发也一样编码不了(比如超过帧的上限), 丢掉, 可以重发的消息由节点重新生成
+                    Err(e) => {
+                        log::error!("[{}] drop {} to peer {}: {}", self.ctx, msg.event.kind(), self.peer, e);
+                        self.queue.lock().unwrap().dropped(Some(&msg));
+                    }
+                }
+            }
+            //PeerQueue丢掉了并且队列已经发完
+            if batch.is_empty() {
+                if self.wake.next().await.is_none() {
+                    return Ok(());
+                }
+                continue;
+            }
+            if let Err(e) = write_frames(socket, &frames).await {
+                self.requeue(batch);
+                return Err(e);
+            }
+            self.metrics.peer_write_batch.record(batch.len() as f64);
+            let depth = self.queue.lock().unwrap().messages.len();
+            self.metrics.peer_queue.set(&[&self.peer], depth as u64);
+        }
+    }
+
+    /// 没发出去的消息按原来的顺序放回队头, 超过上限的部分按push的规则丢掉
+    fn requeue(&self, batch: Vec<Message>) {
+        let mut queue = self.queue.lock().unwrap();
+        for msg in batch.into_iter().rev() {
+            queue.messages.push_front(msg);
+        }
+        let dropped = queue.trim();
+        if dropped > 0 {
+            log::debug!("[{}] queue to peer {} is full, dropped {} requeued messages", self.ctx, self.peer, dropped);
+        }
+    }
+
+    async fn report(&mut self, connected: bool) {
+        self.connected = connected;
+        let event = Event::PeerConnection { peer: self.peer.clone(), connected };
+        self.report_event(event).await;
+    }
+
+    /// 丢掉的日志复制不会有回应, 让leader重新探测peer的进度
+    async fn report_dropped(&mut self) {
+        let dropped = std::mem::take(&mut self.queue.lock().unwrap().appends_dropped);
+        if dropped {
+            let event = Event::ReplicationDropped { peer: self.peer.clone() };
+            self.report_event(event).await;
+        }
+    }
+
+    async fn report_event(&mut self, event: Event) {
+        self.metrics.inbound_queue.inc();
+        //event loop已经退出时不用报告
+        let sent = self.node_tx.send(Message { term: 0, from: Address::Local, to: Address::Local, event }).await;
+        if sent.is_err() {
+            self.metrics.inbound_queue.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Request;
+    use crate::runtime::TcpListener;
+    use crate::transport::{read_frame, Handshake, MAX_FRAME_BYTES};
+
+    fn message(event: Event) -> Message {
+        Message { term: 1, from: Address::Peer("1".to_string()), to: Address::Peer("2".to_string()), event }
+    }
+
+    fn append(base_index: u64) -> Message {
+        message(Event::ReplicateEntries { base_index, base_term: 1, entries: vec![] })
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_append() {
+        let mut queue = Queue::new(2);
+        queue.push(append(1));
+        queue.push(message(Event::GrantVote));
+        let dropped = queue.push(append(2)).unwrap();
+        assert!(matches!(dropped.event, Event::ReplicateEntries { base_index: 1, .. }));
+        assert_eq!(queue.messages.len(), 2);
+        assert!(matches!(queue.messages[0].event, Event::GrantVote));
+    }
+
+    #[test]
+    fn votes_and_responses_never_dropped() {
+        let mut queue = Queue::new(1);
+        queue.push(message(Event::SolicitVote { last_index: 0, last_term: 0 }));
+        //没有可丢的消息时, 新来的可丢消息自己被丢掉
+        assert!(queue.push(append(1)).is_some());
+        assert!(queue.push(message(Event::ClientResponse { id: vec![1], response: Ok(vec![]) })).is_none());
+        assert!(queue.push(message(Event::GrantVote)).is_none());
+        assert_eq!(queue.messages.len(), 3);
+        assert!(queue.messages.iter().all(|m| !droppable(m)));
+    }
+
+    #[test]
+    fn full_queue_bounces_request() {
+        let mut queue = Queue::new(1);
+        queue.push(message(Event::GrantVote));
+        let request = message(Event::ClientRequest { id: vec![1], request: Request::Propose(vec![]) });
+        let bounced = queue.push(request).unwrap();
+        assert!(matches!(bounced.event, Event::ClientRequest { .. }));
+        assert_eq!(queue.messages.len(), 1);
+    }
+
+    #[test]
+    fn trim_to_capacity() {
+        let mut queue = Queue::new(2);
+        for i in 0..2 {
+            queue.push(append(i));
+        }
+        //写失败放回队头的消息让队列超过了上限
+        queue.messages.push_front(message(Event::GrantVote));
+        queue.messages.push_front(append(9));
+        assert_eq!(queue.trim(), 2);
+        assert_eq!(queue.messages.len(), 2);
+        assert!(matches!(queue.messages[0].event, Event::GrantVote));
+        assert!(matches!(queue.messages[1].event, Event::ReplicateEntries { base_index: 1, .. }));
+    }
+
+    #[test]
+    fn dropped_appends_flagged() {
+        let mut queue = Queue::new(1);
+        queue.push(message(Event::GrantVote));
+        assert!(queue.push(message(Event::Heartbeat { commit_index: 0, commit_term: 0, read_seq: 0 })).is_some());
+        assert!(!queue.appends_dropped);
+        assert!(queue.push(append(1)).is_some());
+        assert!(queue.appends_dropped);
+
+        //放回队头后裁掉的也算
+        let mut queue = Queue::new(1);
+        queue.push(message(Event::GrantVote));
+        queue.messages.push_front(append(1));
+        assert_eq!(queue.trim(), 1);
+        assert!(queue.appends_dropped);
+    }
+
+    /// 已经排队的消息合并成一次写发出去
+    #[test]
+    fn queued_frames_in_one_write() {
+        runtime::block_on(async {
+            let addr = "127.0.0.1:19720";
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let (node_tx, _node_rx) = mpsc::channel(8);
+            let metrics = Arc::new(Metrics::new());
+            let endpoint = Endpoint::new(Handshake::node("test", "1"), None);
+            let ctx = Arc::new(LogContext::new("1", 1, "leader"));
+            let (mut sender, mut queue) = PeerSender::new(
+                ctx, "2".to_string(), addr.to_string(), 16, endpoint, node_tx, metrics.clone());
+            for i in 1..=5 {
+                queue.push(append(i));
+            }
+            //队列丢掉后发送任务发完就结束
+            drop(queue);
+
+            let mut socket = Stream::Plain(runtime::connect(addr).await.unwrap());
+            let (mut peer, _) = listener.accept().await.unwrap();
+            sender.send_queued(&mut socket).await.unwrap();
+            for i in 1..=5 {
+                let msg: Message = read_frame(&mut peer, MAX_FRAME_BYTES).await.unwrap().unwrap();
+                assert!(matches!(msg.event, Event::ReplicateEntries { base_index, .. } if base_index == i));
+            }
+
+            let rendered = metrics.render();
+            assert!(rendered.contains("iraft_peer_write_batch_size_count 1\n"), "{}", rendered);
+            assert!(rendered.contains("iraft_peer_write_batch_size_sum 5\n"), "{}", rendered);
+        });
+    }
+}
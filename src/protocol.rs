@@ -0,0 +1,95 @@
+//! 客户端协议, 在listen_client端口上. 帧格式和节点之间的一样: 4字节大端长度 + bincode编码的值.
+//! 连接建立后(配置了TLS时先做TLS握手)客户端先发握手帧, 节点回复Ok(握手)或者Err(原因);
+//! 之后客户端发ClientRequest, 节点回ClientReply. 每个请求带客户端分配的id, 回复带同样的id,
+//! 一个连接上可以同时有多个请求在处理, 回复按完成的顺序发出
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::log::log::ConfigChange;
+use crate::message::RequestError;
+use crate::node::Status;
+use crate::transport;
+
+/// 客户端发给节点的请求
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientRequest {
+    /// 用于匹配回复, 由客户端分配, 同一连接上还没回复的请求之间不能重复
+    pub id: u64,
+    pub op: Operation,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    /// 提交命令, 应用到状态机后回复状态机的结果
+    Propose(Vec<u8>),
+    /// 线性一致读
+    Read(Vec<u8>),
+    /// 查询节点状态, 任何节点都可以回复
+    Status,
+    /// 把leader转移给指定节点, None时选复制进度最快的
+    TransferLeadership(Option<String>),
+    /// 增删节点
+    ChangeMembership(ConfigChange),
+    /// 注册客户端会话, 回复Reply::Session
+    RegisterSession,
+    /// 带会话提交命令, 同一会话的同一个seq只应用一次, 重试时返回第一次的结果
+    SessionPropose { client_id: u64, seq: u64, command: Vec<u8> },
+}
+
+/// 节点对一个请求的回复
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientReply {
+    pub id: u64,
+    pub result: Result<Reply, RequestError>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Reply {
+    /// Propose, SessionPropose和Read的结果
+    Value(Vec<u8>),
+    Status(Box<Status>),
+    /// 运维操作完成
+    Done,
+    /// 注册的会话id
+    Session(u64),
+}
+
+impl Operation {
+    /// 操作名, 用于日志和指标
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Propose(_) => "propose",
+            Operation::Read(_) => "read",
+            Operation::Status => "status",
+            Operation::TransferLeadership(_) => "transfer_leadership",
+            Operation::ChangeMembership(_) => "change_membership",
+            Operation::RegisterSession => "register_session",
+            Operation::SessionPropose { .. } => "session_propose",
+        }
+    }
+}
+
+impl ClientRequest {
+    /// 编码成一帧的内容(不含长度前缀)
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// 解码一帧, 和Message::decode一样有长度限制
+    pub fn decode(bytes: &[u8]) -> Result<ClientRequest> {
+        transport::decode(bytes)
+    }
+}
+
+impl ClientReply {
+    /// 编码成一帧的内容(不含长度前缀)
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// 解码一帧, 和Message::decode一样有长度限制
+    pub fn decode(bytes: &[u8]) -> Result<ClientReply> {
+        transport::decode(bytes)
+    }
+}
@@ -0,0 +1,199 @@
+//! 异步运行时的薄封装, 用cargo feature选择: rt-async-std(默认)或rt-tokio, 都开启时用tokio.
+//! 其他模块只通过这里spawn任务, 定时和建立TCP连接; TCP流统一实现futures的AsyncRead/AsyncWrite
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{FutureExt, Stream};
+
+#[cfg(not(any(feature = "rt-async-std", feature = "rt-tokio")))]
+compile_error!("enable one of the runtime features: rt-async-std, rt-tokio");
+
+/// TCP连接
+#[cfg(not(feature = "rt-tokio"))]
+pub type TcpStream = async_std::net::TcpStream;
+
+/// TCP连接. Compat没有转发vectored写, 这里直接调用tokio的
+#[cfg(feature = "rt-tokio")]
+#[derive(Debug)]
+pub struct TcpStream(tokio_util::compat::Compat<tokio::net::TcpStream>);
+
+#[cfg(feature = "rt-tokio")]
+impl futures::AsyncRead for TcpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl futures::AsyncWrite for TcpStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write_vectored(Pin::new(self.0.get_mut()), cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+/// 在当前运行时上运行任务. 丢掉JoinHandle任务继续运行
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(not(feature = "rt-tokio"))]
+    return JoinHandle(async_std::task::spawn(future));
+    #[cfg(feature = "rt-tokio")]
+    return JoinHandle(tokio::spawn(future));
+}
+
+/// 在新建的运行时上运行future直到完成, 用于程序入口
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    #[cfg(not(feature = "rt-tokio"))]
+    return async_std::task::block_on(future);
+    #[cfg(feature = "rt-tokio")]
+    return tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build tokio runtime")
+        .block_on(future);
+}
+
+/// 一直保留的运行时, 给同步接口用: 在它上面spawn的任务在两次block_on之间继续运行
+pub struct Runtime(
+    #[cfg(feature = "rt-tokio")] tokio::runtime::Runtime,
+);
+
+impl Runtime {
+    pub fn new() -> io::Result<Runtime> {
+        #[cfg(not(feature = "rt-tokio"))]
+        return Ok(Runtime());
+        #[cfg(feature = "rt-tokio")]
+        return Ok(Runtime(tokio::runtime::Builder::new_multi_thread().enable_all().build()?));
+    }
+
+    /// 运行future直到完成, 不能在异步上下文里调用
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        #[cfg(not(feature = "rt-tokio"))]
+        return async_std::task::block_on(future);
+        #[cfg(feature = "rt-tokio")]
+        return self.0.block_on(future);
+    }
+}
+
+/// spawn出来的任务, await得到任务的结果
+pub struct JoinHandle<T>(
+    #[cfg(not(feature = "rt-tokio"))] async_std::task::JoinHandle<T>,
+    #[cfg(feature = "rt-tokio")] tokio::task::JoinHandle<T>,
+);
+
+impl<T> JoinHandle<T> {
+    /// 取消任务并等它结束
+    pub async fn cancel(self) {
+        #[cfg(not(feature = "rt-tokio"))]
+        self.0.cancel().await;
+        #[cfg(feature = "rt-tokio")]
+        {
+            self.0.abort();
+            let _ = self.0.await;
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        #[cfg(not(feature = "rt-tokio"))]
+        return self.0.poll_unpin(cx);
+        //任务panic时在等待方继续panic, 和async-std一致
+        #[cfg(feature = "rt-tokio")]
+        return self.0.poll_unpin(cx).map(|r| match r {
+            Ok(v) => v,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => panic!("task failed: {}", e),
+        });
+    }
+}
+
+pub async fn sleep(duration: Duration) {
+    #[cfg(not(feature = "rt-tokio"))]
+    async_std::task::sleep(duration).await;
+    #[cfg(feature = "rt-tokio")]
+    tokio::time::sleep(duration).await;
+}
+
+/// 每隔period产生一次, 第一次在period之后
+pub fn interval(period: Duration) -> impl Stream<Item = ()> + Unpin {
+    #[cfg(not(feature = "rt-tokio"))]
+    return async_std::stream::interval(period);
+    #[cfg(feature = "rt-tokio")]
+    return Box::pin(futures::stream::unfold(
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period),
+        |mut interval| async move {
+            interval.tick().await;
+            Some(((), interval))
+        },
+    ));
+}
+
+/// 超时返回TimedOut错误
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> io::Result<F::Output> {
+    futures::select! {
+        v = future.fuse() => Ok(v),
+        _ = sleep(duration).fuse() => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    #[cfg(not(feature = "rt-tokio"))]
+    return async_std::net::TcpStream::connect(addr).await;
+    #[cfg(feature = "rt-tokio")]
+    {
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+        Ok(TcpStream(tokio::net::TcpStream::connect(addr).await?.compat()))
+    }
+}
+
+/// 监听TCP连接
+pub struct TcpListener(
+    #[cfg(not(feature = "rt-tokio"))] async_std::net::TcpListener,
+    #[cfg(feature = "rt-tokio")] tokio::net::TcpListener,
+);
+
+impl TcpListener {
+    pub async fn bind(addr: &str) -> io::Result<TcpListener> {
+        #[cfg(not(feature = "rt-tokio"))]
+        return Ok(TcpListener(async_std::net::TcpListener::bind(addr).await?));
+        #[cfg(feature = "rt-tokio")]
+        return Ok(TcpListener(tokio::net::TcpListener::bind(addr).await?));
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        #[cfg(not(feature = "rt-tokio"))]
+        return self.0.accept().await;
+        #[cfg(feature = "rt-tokio")]
+        {
+            use tokio_util::compat::TokioAsyncReadCompatExt;
+            let (stream, addr) = self.0.accept().await?;
+            Ok((TcpStream(stream.compat()), addr))
+        }
+    }
+}
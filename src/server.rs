@@ -1,145 +1,529 @@
-use std::collections::HashMap;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use async_std::{
-    net::{TcpListener, TcpStream},
-    prelude::*,
-};
-use futures::{AsyncBufReadExt, FutureExt, sink::SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures::channel::mpsc;
-use futures::io::BufReader;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either, Fuse, FusedFuture};
 
-use crate::conf::Config;
-use crate::message::{Address, Event, Message};
-use crate::node::Node;
+use crate::conf::{Config, OverloadPolicy, StorageKind, SyncPolicy};
+use crate::handle::{Call, CallSender, Instruction, RaftHandle};
+use crate::message::{Address, Event, Message, Request, RequestError, Response};
+use crate::node::{Node, Role, Status};
+use crate::node::ready::Ready;
+use crate::node::leader::Leader;
+use crate::log::Store;
+use crate::log::file_store::FileStore;
 use crate::log::memory_store::MemoryStore;
-use crate::log::log::Log;
+use crate::log::log::{Command, Entry, LogStore};
+use crate::log::worker::StoreWorker;
+use crate::logging::LogContext;
+use crate::metrics::{self, Metrics};
+use crate::notify::{Notifier, Subscription};
+use crate::state::State;
+use crate::peer::{PeerQueue, PeerSender};
+use crate::runtime::{self, TcpListener, TcpStream};
+use crate::service::client_connection;
+use crate::session::Sessions;
+use crate::tls::{Stream, Tls};
+use crate::transport::{read_frame, write_frame, BadFrame, Endpoint, Handshake, MAX_FRAME_BYTES};
 
-const TICK: Duration = Duration::from_millis(10000);
+/// 停止时等待发送任务把剩余消息发完的时间, 超时直接关闭连接
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
 
 pub struct RaftServer {
     node: Node,
-    node_rx: UnboundedReceiver<Message>,
+    //node的Ready由存储线程持久化, 完成后在event loop里应用
+    store: StoreWorker,
+    store_done: UnboundedReceiver<Result<()>>,
+    state: Box<dyn State>,
+    sessions: Sessions,
     conf: Config,
+    metrics: Arc<Metrics>,
+    notifier: Arc<Notifier>,
+    request_tx: CallSender,
+    request_rx: mpsc::Receiver<Call>,
+    instruction_tx: UnboundedSender<Instruction>,
+    instruction_rx: UnboundedReceiver<Instruction>,
+    tls: Option<Tls>,
+    //网络任务日志里的任期和角色, event loop更新
+    ctx: Arc<LogContext>,
 }
 
 impl RaftServer {
-    pub async fn new(conf: Config) -> RaftServer {
-        //通道的两头, 接收方给RaftNode(当前节点),
-        // 当此node需要发送消息给peer,
-        // 从tx发送消息,在event_loop中的rx收到消息再发送出去
-        let (node_tx, node_rx) = mpsc::unbounded();
-        let log = Log::new(Box::new(MemoryStore::new()));
-        let peers: Vec<String> = conf.peers.keys().map(|k| k.clone()).collect();
-        RaftServer {
-            node: Node::new(conf.id.clone(), log, peers, node_tx).await.unwrap(),
-            node_rx,
-            conf,
+    pub async fn new(conf: Config, state: Box<dyn State>) -> Result<RaftServer> {
+        let metrics = Arc::new(Metrics::new());
+        let notifier = Arc::new(Notifier::new());
+        conf.validate()?;
+        //证书有问题时启动就失败, 不要等到连接时
+        let tls = conf.tls.as_ref().map(Tls::load).transpose()?;
+        let store: Box<dyn Store> = match conf.storage {
+            StorageKind::Memory => Box::new(MemoryStore::new()),
+            StorageKind::File => Box::new(FileStore::open(&conf.data_dir, conf.sync.policy != SyncPolicy::Os)?),
+        };
+        let store = LogStore::new(store, metrics.clone());
+        let (log, hard_state, peers) = store.load()?;
+        //会话表跟着状态机, 用状态机已应用的log重建
+        let mut sessions = Sessions::new(conf.session.timeout_ms);
+        sessions.restore(log.scan(..=state.applied_index()));
+        metrics.sessions.set(sessions.len() as u64);
+        let (store, store_done) = StoreWorker::spawn(&conf.id, store, conf.sync.clone(), metrics.clone())?;
+        //成员变更过的话, 以保存的成员为准
+        let peers = peers.unwrap_or_else(|| conf.peers.clone());
+        let forward_timeout = conf.forward.enabled.then(|| Duration::from_millis(conf.forward.timeout_ms));
+        let node = Node::new(
+            conf.id.clone(), log, hard_state, peers, state.applied_index(), conf.replication.clone(),
+            forward_timeout, metrics.clone(), notifier.clone(),
+        ).await?;
+        node.report_metrics(&metrics);
+        let ctx = Arc::new(LogContext::new(&conf.id, node.term(), node.role_name()));
+        if tls.is_none() {
+            log::warn!("[{}] TLS is off: peer node ids in handshakes are not verified, \
+                any process that knows the cluster id can pose as a peer", ctx);
         }
+        let (request_tx, request_rx) = mpsc::channel(conf.queues.requests);
+        let request_tx = Arc::new(futures::lock::Mutex::new(request_tx));
+        let (instruction_tx, instruction_rx) = mpsc::unbounded();
+        Ok(RaftServer {
+            node,
+            store,
+            store_done,
+            state,
+            sessions,
+            conf,
+            metrics,
+            notifier,
+            request_tx,
+            request_rx,
+            instruction_tx,
+            instruction_rx,
+            tls,
+            ctx,
+        })
+    }
+
+    /// 在后台运行节点, 返回句柄. 通过句柄提交请求, 用RaftHandle::shutdown停止
+    pub fn start(self) -> RaftHandle {
+        let handle = self.handle();
+        let (_, client_rx) = mpsc::channel(0);
+        runtime::spawn(async move {
+            let ctx = self.ctx.clone();
+            if let Err(e) = self.serve(client_rx).await {
+                log::error!("[{}] raft server failed: {}", ctx, e);
+            }
+        });
+        handle
+    }
+
+    /// 运行中节点的句柄, serve之前获取
+    pub fn handle(&self) -> RaftHandle {
+        RaftHandle::new(
+            self.request_tx.clone(),
+            self.instruction_tx.clone(),
+            self.notifier.clone(),
+            self.metrics.clone(),
+            self.conf.queues.overload,
+        )
+    }
+
+    /// 订阅节点事件, 见RaftHandle::subscribe
+    pub fn subscribe(&self) -> Subscription {
+        self.notifier.subscribe()
+    }
+
+    /// 本节点的指标
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
     }
 
     //此函数处理三个功能:
     // 1, 作为server角色, 监听接收其他节点的消息
     // 2, 作为client角色, 发送消息给其他节点
-    // 3, 作为整个server, 接收外部client的请求
-    pub async fn serve(self, client_rx: UnboundedReceiver<Message>) -> Result<()> {
+    // 3, 作为整个server, 接收外部client的请求: 进程内的client_rx, 以及listen_client上的客户端连接
+    //
+    // 通过RaftHandle::shutdown停止后返回节点的最终状态
+    pub async fn serve(self, client_rx: mpsc::Receiver<Message>) -> Result<Status> {
+        //指标端口在启动任何任务之前绑定, 绑定失败时直接返回错误
+        let metrics_listener = match self.conf.listen_metrics.as_deref() {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await
+                    .map_err(|e| anyhow::anyhow!("bind metrics listener {}: {}", addr, e))?;
+                log::info!("[{}] serving metrics on http://{}/metrics", self.ctx, addr);
+                Some(listener)
+            }
+            None => None,
+        };
 
         //1, 接收其他Node的TCP请求, 以server的角色
-        let (tcp_in_tx, tcp_in_rx) = mpsc::unbounded();
+        let (tcp_in_tx, tcp_in_rx) = mpsc::channel(self.conf.queues.inbound);
         let addr = self.conf.listen_raft.clone();
-        let (task, receive) = RaftServer::tcp_receive(addr, tcp_in_tx).remote_handle();
-        async_std::task::spawn(task);
+        let endpoint = Endpoint::new(Handshake::node(&self.conf.cluster_id, &self.conf.id), self.tls.clone());
+        let (task, receive) = RaftServer::tcp_receive(
+            self.ctx.clone(), addr, endpoint.clone(), tcp_in_tx.clone(), self.handle(), self.metrics.clone()
+        ).remote_handle();
+        runtime::spawn(task);
 
         //2,
-        let (tcp_out_tx, tcp_out_rx) = mpsc::unbounded();
-        let (task, send) = RaftServer::tcp_sender(self.conf.id.clone(),self.conf.peers.clone(), tcp_out_rx).remote_handle();
-        async_std::task::spawn(task);
+        let (tcp_out_tx, tcp_out_rx) = mpsc::channel(self.conf.queues.outbound);
+        let peers = self.node.peers().clone();
+        let (task, send) = RaftServer::tcp_sender(
+            self.ctx.clone(), peers, self.conf.queues.peer, endpoint.clone(), tcp_out_rx, tcp_in_tx, self.metrics.clone()
+        ).remote_handle();
+        runtime::spawn(task);
+
+        //3, 客户端协议
+        let client = match self.conf.listen_client.clone() {
+            Some(addr) => {
+                let (task, client) = RaftServer::client_receive(
+                    self.ctx.clone(), addr, endpoint.clone(), self.handle(), self.metrics.clone()
+                ).remote_handle();
+                runtime::spawn(task);
+                client.boxed()
+            }
+            None => future::pending().boxed(),
+        };
+
+        if let Some(listener) = metrics_listener {
+            let ctx = self.ctx.clone();
+            let metrics = self.metrics.clone();
+            runtime::spawn(async move {
+                if let Err(e) = metrics::serve(listener, metrics).await {
+                    log::error!("[{}] metrics listener failed: {}", ctx, e);
+                }
+            });
+        }
 
         //集中处理所有请求, 节点之间以及client的请求
         //用channel链接此函数与send,receive两函数
         let (task, event_loop) = self.event_loop( tcp_in_rx, tcp_out_tx, client_rx)
             .remote_handle();
-        async_std::task::spawn(task);
+        runtime::spawn(task);
 
-        futures::join!(event_loop, receive, send);
-
-        Ok(())
+        //event loop退出(shutdown)或者网络任务出错时结束, 丢弃remote handle会取消其余任务
+        let mut event_loop = event_loop.fuse();
+        let mut receive = receive.fuse();
+        let mut client = client.fuse();
+        let mut send = send.fuse();
+        let result = futures::select! {
+            r = event_loop => r,
+            r = receive => return Err(r.err().unwrap_or_else(|| anyhow::anyhow!("peer listener stopped"))),
+            r = client => return Err(r.err().unwrap_or_else(|| anyhow::anyhow!("client listener stopped"))),
+            //发送任务正常结束说明event loop已经退出, 取它的结果
+            r = send => { r?; event_loop.await }
+        };
+        //停止或者出了致命错误: 不再接受新连接; event loop丢掉了发送通道, 等发送任务发完剩余消息并关闭连接
+        drop(receive);
+        drop(client);
+        if !send.is_terminated() {
+            send.await?;
+        }
+        result
     }
 
     async fn event_loop(
         self,
-        tcp_in_rx: UnboundedReceiver<Message>, //其他node请求的接收通道
-        tcp_out_tx: UnboundedSender<Message>, //本节点向其他节点的发送通道
+        mut tcp_in_rx: mpsc::Receiver<Message>, //其他node请求的接收通道
+        mut tcp_out_tx: mpsc::Sender<Message>, //本节点向其他节点的发送通道
         //来自客户端的请求接收通道(发送端在外部逻辑处理处), 如查询请求
-        client_rx: UnboundedReceiver<Message>,
-    ) -> Result<()> {
-        let mut client_rx = UnboundedReceiver::from(client_rx);
-        let mut node_rx = UnboundedReceiver::from(self.node_rx);
-        let mut tcp_in_rx = UnboundedReceiver::from(tcp_in_rx);
+        mut client_rx: mpsc::Receiver<Message>,
+    ) -> Result<Status> {
+        let store = self.store;
+        let mut store_done = self.store_done;
+        let mut state = self.state;
+        let mut sessions = self.sessions;
+        //取出来还没处理完的Ready, 和它的写入是否还在进行
+        let mut readies: VecDeque<(Ready, bool)> = VecDeque::new();
+        let mut request_rx = self.request_rx;
+        let mut instruction_rx = self.instruction_rx;
+        let metrics = self.metrics;
+        let ctx = self.ctx;
+        let max_requests = self.conf.queues.requests;
+        let overload = self.conf.queues.overload;
+        let batch = self.conf.batch.clone();
+        let batch_delay = Duration::from_micros(batch.max_delay_us);
+        //句柄发起的请求, 请求id -> 等待结果的句柄
+        let mut pending: HashMap<Vec<u8>, oneshot::Sender<Response>> = HashMap::new();
+        let mut next_id: u64 = 0;
+        //收到shutdown后进入停止状态, 等待最终状态的句柄
+        let mut stopping: Vec<oneshot::Sender<Status>> = vec![];
+        //停止前的leader转移, 完成(或失败)后才退出
+        let mut transfer = Fuse::terminated();
+
+        //致命错误(如存储失败): node已经不可用, 回复所有等待中的请求后退出, 由serve关闭网络任务
+        macro_rules! fatal {
+            ($result:expr) => {
+                match $result {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("[{}] fatal error, shutting down: {}", ctx, e);
+                        for (_, tx) in pending.drain() {
+                            let _ = tx.send(Err(RequestError::Internal(format!("node failed: {}", e))));
+                        }
+                        return Err(e.into());
+                    }
+                }
+            };
+        }
 
-        let mut tick = async_std::stream::interval(TICK);
+        let mut tick = runtime::interval(Duration::from_millis(self.conf.tick_ms));
         //在tick/step的时候,node的角色会改变,不同的角色会有不同的事件发生
         let mut node = self.node;
+        //合并窗口里攒下的请求和窗口结束的时间. 等待时event loop照常处理其他消息
+        let mut calls: Vec<Call> = vec![];
+        let mut calls_deadline: Option<Instant> = None;
+
+        //把攒下的请求一起交给节点, 停止中的节点直接回复Stopped
+        macro_rules! step_calls {
+            () => {{
+                calls_deadline = None;
+                metrics.request_batch_size.record(calls.len() as f64);
+                for (request, tx) in calls.drain(..) {
+                    if !stopping.is_empty() {
+                        let _ = tx.send(Err(RequestError::Stopped));
+                    } else if pending.len() >= max_requests {
+                        metrics.requests_overloaded.inc();
+                        let _ = tx.send(Err(RequestError::Overloaded));
+                    } else {
+                        next_id += 1;
+                        let id = next_id.to_be_bytes().to_vec();
+                        pending.insert(id.clone(), tx);
+                        node = fatal!(node.step(client_request(id, request)))
+                    }
+                }
+            }};
+        }
+
+        //重启后先把已提交还没应用的log应用掉
+        fatal!(drive(&mut node, &store, &mut readies, &mut *state, &mut sessions, &mut pending, &mut tcp_out_tx, &metrics).await);
         loop {
+            //阻塞策略下在途请求满了就先不取新请求, 调用方在请求队列上等待
+            let accept = !stopping.is_empty() || overload == OverloadPolicy::Reject || pending.len() + calls.len() < max_requests;
             futures::select! {
-                _ = tick.next().fuse() => node = node.tick()?,
-                //处理其他node发送过来的消息
-                msg = tcp_in_rx.next().fuse() => match msg{
-                    Some(msg) => node = node.step(msg)?,
-                    None => (),
-                },
-                //接收从RaftNode(自己)过来的消息, 转发到send函数处理
-                msg = node_rx.next().fuse() => match msg {
-                    Some(msg) => {tcp_out_tx.unbounded_send(msg)?},
-                    None =>(),
+                _ = tick.next().fuse() => node = fatal!(node.tick()),
+                //转发的请求按各自的截止时间超时, 不受tick间隔影响
+                _ = sleep_until(node.forward_deadline()).fuse() => fatal!(node.expire_forwards(Instant::now())),
+                //处理其他node发送过来的消息, 不合法的丢掉, 节点继续运行
+                msg = tcp_in_rx.select_next_some() => {
+                    metrics.inbound_queue.dec();
+                    log::trace!("[node={} term={} role={}] received {:?}",
+                        node.id(), node.term(), node.role_name(), msg);
+                    metrics.messages.inc(&["in", msg.event.kind()]);
+                    match node.validate(&msg) {
+                        Ok(()) => node = fatal!(node.step(msg)),
+                        Err(e) => {
+                            metrics.invalid_messages.inc();
+                            log::warn!("[node={} term={} role={}] drop invalid message from {:?}: {}",
+                                node.id(), node.term(), node.role_name(), msg.from, e);
+                        }
+                    }
                 },
                 //接收client发来的消息
-                msg = client_rx.next().fuse() => match msg {
-                    Some(msg) => {println!("from client:{:?}", msg); ()},
-                    None=> (),
+                mut msg = client_rx.select_next_some() => {
+                    log::debug!("[node={} term={} role={}] client request {:?}",
+                        node.id(), node.term(), node.role_name(), msg);
+                    msg.from = Address::Client;
+                    match node.validate(&msg) {
+                        Ok(()) if stopping.is_empty() => node = fatal!(node.step(msg)),
+                        Ok(()) => (),
+                        Err(e) => log::warn!("[node={} term={} role={}] drop invalid client message: {}",
+                            node.id(), node.term(), node.role_name(), e),
+                    }
+                },
+                //来自RaftHandle的请求, 和已经排队的以及合并窗口内到达的请求一起交给节点
+                call = next_call(&mut request_rx, accept).fuse() => {
+                    calls.push(call);
+                    //阻塞策略下不多取放不下的请求
+                    let room = match overload {
+                        OverloadPolicy::Block => max_requests.saturating_sub(pending.len()),
+                        OverloadPolicy::Reject => usize::MAX,
+                    };
+                    let max = batch.max_size.min(room);
+                    while calls.len() < max {
+                        match request_rx.try_next() {
+                            Ok(Some(call)) => calls.push(call),
+                            _ => break,
+                        }
+                    }
+                    if calls.len() >= max || batch_delay.is_zero() || !stopping.is_empty() {
+                        step_calls!();
+                    } else if calls_deadline.is_none() {
+                        calls_deadline = Some(Instant::now() + batch_delay);
+                    }
+                },
+                _ = sleep_until(calls_deadline).fuse() => step_calls!(),
+                instruction = instruction_rx.select_next_some() => match instruction {
+                    Instruction::Status(tx) => { let _ = tx.send(node.status()); }
+                    Instruction::Shutdown { transfer_leadership, tx } => {
+                        log::info!("[node={} term={} role={}] shutting down",
+                            node.id(), node.term(), node.role_name());
+                        let first = stopping.is_empty();
+                        stopping.push(tx);
+                        //合并窗口里的请求不再等
+                        if !calls.is_empty() {
+                            step_calls!();
+                        }
+                        if first && transfer_leadership && node.role_name() == Leader::NAME && !node.peers().is_empty() {
+                            next_id += 1;
+                            let id = next_id.to_be_bytes().to_vec();
+                            let (tx, rx) = oneshot::channel();
+                            pending.insert(id.clone(), tx);
+                            transfer = rx.fuse();
+                            node = fatal!(node.step(client_request(id, Request::TransferLeadership(None))))
+                        }
+                    }
+                },
+                //存储线程按提交顺序完成写入
+                done = store_done.select_next_some() => {
+                    fatal!(done);
+                    fatal!(written(&mut readies));
+                },
+                r = transfer => match r {
+                    Ok(Ok(_)) => log::info!("[node={} term={} role={}] leadership transferred before shutdown",
+                        node.id(), node.term(), node.role_name()),
+                    Ok(Err(e)) => log::warn!("[node={} term={} role={}] leadership transfer failed: {}",
+                        node.id(), node.term(), node.role_name(), e),
+                    Err(_) => (),
+                },
+            }
+            fatal!(drive(&mut node, &store, &mut readies, &mut *state, &mut sessions, &mut pending, &mut tcp_out_tx, &metrics).await);
+            node.report_metrics(&metrics);
+            ctx.update(node.term(), node.role_name());
+            if !stopping.is_empty() && transfer.is_terminated() {
+                break;
+            }
+        }
+
+        for (_, tx) in pending.drain() {
+            let _ = tx.send(Err(RequestError::Stopped));
+        }
+        //等还在进行的写入完成, 再刷盘
+        while readies.iter().any(|(_, writing)| *writing) {
+            match store_done.next().await {
+                Some(done) => {
+                    fatal!(done);
+                    fatal!(written(&mut readies));
+                    fatal!(drive(&mut node, &store, &mut readies, &mut *state, &mut sessions, &mut pending, &mut tcp_out_tx, &metrics).await);
                 }
+                None => fatal!(Err(anyhow::anyhow!("store worker stopped"))),
             }
         }
+        fatal!(store.flush().await);
+        let status = node.status();
+        log::info!("[node={} term={} role={}] stopped, last_index={} commit_index={} applied_index={}",
+            node.id(), node.term(), node.role_name(), status.last_index, status.commit_index, status.applied_index);
+        for tx in stopping {
+            let _ = tx.send(status.clone());
+        }
+        Ok(status)
     }
 
     /// 监听其他节点消息
-    async fn tcp_receive(addr: String, out_rx: UnboundedSender<Message>) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        let mut incoming = listener.incoming();
-        while let Some(stream) = incoming.next().await {
-            let stream = stream.unwrap();
+    async fn tcp_receive(
+        ctx: Arc<LogContext>,
+        addr: String,
+        endpoint: Endpoint,
+        out_rx: mpsc::Sender<Message>,
+        handle: RaftHandle,
+        metrics: Arc<Metrics>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(&addr).await?;
+        log::info!("[{}] listening for peers on {}", ctx, addr);
+        loop {
+            let (stream, peer) = accept(&ctx, &listener, "raft", &metrics).await;
             let out_rx = out_rx.clone();
-            async_std::task::spawn(connection_loop(out_rx, stream));
+            let ctx = ctx.clone();
+            let handle = handle.clone();
+            let metrics = metrics.clone();
+            let endpoint = endpoint.clone();
+            runtime::spawn(async move {
+                log::debug!("[{}] accepted connection from {}", ctx, peer);
+                let task = connection_loop(out_rx, stream, endpoint, handle, metrics.clone());
+                if let Err(e) = isolate(task, &metrics).await {
+                    log::warn!("[{}] connection from {} closed: {}", ctx, peer, e);
+                }
+            });
         }
-        Ok(())
     }
 
+    /// 监听客户端连接
+    async fn client_receive(
+        ctx: Arc<LogContext>,
+        addr: String,
+        endpoint: Endpoint,
+        handle: RaftHandle,
+        metrics: Arc<Metrics>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(&addr).await?;
+        log::info!("[{}] listening for clients on {}", ctx, addr);
+        loop {
+            let (stream, client) = accept(&ctx, &listener, "client", &metrics).await;
+            let ctx = ctx.clone();
+            let handle = handle.clone();
+            let metrics = metrics.clone();
+            let endpoint = endpoint.clone();
+            runtime::spawn(async move {
+                log::debug!("[{}] accepted client connection from {}", ctx, client);
+                let task = client_connection(stream, endpoint, handle, metrics.clone());
+                if let Err(e) = isolate(task, &metrics).await {
+                    log::info!("[{}] client connection from {} closed: {}", ctx, client, e);
+                }
+            });
+        }
+    }
 
-    /// 此node向其他节点的消息处理逻辑
+    /// 此node向其他节点的消息处理逻辑. 只往各peer的队列里放消息, 不会被慢的peer卡住
     async fn tcp_sender(
-        node_id: String,
+        ctx: Arc<LogContext>,
         peers: HashMap<String, String>,
-        out_tx: UnboundedReceiver<Message>,
+        queue_capacity: usize,
+        endpoint: Endpoint,
+        mut out_tx: mpsc::Receiver<Message>,
+        //连接状态报告给event loop
+        node_tx: mpsc::Sender<Message>,
+        metrics: Arc<Metrics>,
     ) -> Result<()> {
         //此node向外部node发送的消息会来自此通道
-        let mut out_tx = UnboundedReceiver::from(out_tx);
-        let mut peer_txs = HashMap::new();
-
-        for (id, addr) in peers.into_iter() {
-            let (tx, rx) = mpsc::unbounded();
-            peer_txs.insert(id, tx);
-            async_std::task::spawn(
-                send_message_to_peer(addr, rx)
+        let mut peer_txs: HashMap<String, PeerQueue> = HashMap::new();
+        let mut tasks = vec![];
+        let mut spawn_sender = |id: &str, addr: &str| {
+            let (sender, queue) = PeerSender::new(
+                ctx.clone(), id.to_string(), addr.to_string(), queue_capacity, endpoint.clone(), node_tx.clone(),
+                metrics.clone(),
             );
+            let (ctx, peer, metrics) = (ctx.clone(), id.to_string(), metrics.clone());
+            tasks.push(runtime::spawn(async move {
+                //发送任务panic或出错后这个peer不再发送, 节点继续运行
+                if let Err(e) = isolate(sender.run(), &metrics).await {
+                    log::error!("[{}] sender to peer {} failed: {}", ctx, peer, e);
+                }
+            }));
+            queue
+        };
+
+        for (id, addr) in peers.iter() {
+            peer_txs.insert(id.clone(), spawn_sender(id, addr));
         }
 
         while let Some(mut msg) = out_tx.next().await {
+            metrics.outbound_queue.dec();
+            //成员变更: 给新节点起发送任务, 删掉的节点丢掉队列, 发送任务随之结束
+            if let Event::PeersChanged { peers } = &msg.event {
+                peer_txs.retain(|id, _| peers.contains_key(id));
+                for (id, addr) in peers.iter() {
+                    if !peer_txs.contains_key(id) {
+                        peer_txs.insert(id.clone(), spawn_sender(id, addr));
+                    }
+                }
+                continue;
+            }
             let to: Vec<String> = peer_txs.keys().cloned().collect();
             if msg.from == Address::Local {
-                msg.from = Address::Peer(node_id.clone());
+                msg.from = Address::Peer(ctx.id().to_string());
             }
             let node_id_to = match &msg.to {
                 Address::Peer(peer) => vec![peer.to_string()],
@@ -147,46 +531,243 @@ impl RaftServer {
                 _ => vec![],
             };
             for id in node_id_to {
-                let send = peer_txs.get_mut(&id).unwrap();
-                send.unbounded_send(msg.clone());
+                match peer_txs.get_mut(&id) {
+                    Some(queue) => queue.push(msg.clone()),
+                    None => log::warn!("[{}] drop message to unknown peer {}", ctx, id),
+                }
             }
         }
+
+        //event loop已退出: 丢掉队列让发送任务发完剩余消息后结束, 超时的直接取消
+        drop(peer_txs);
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        for task in tasks {
+            let timeout = Box::pin(runtime::sleep(deadline.saturating_duration_since(Instant::now())));
+            if let Either::Right((_, task)) = future::select(task, timeout).await {
+                task.cancel().await;
+            }
+        }
+        log::debug!("[{}] peer connections closed", ctx);
         Ok(())
     }
 }
 
-
-async fn send_message_to_peer(addr: String, rx: UnboundedReceiver<Message>) -> Result<()> {
-    let mut rx = UnboundedReceiver::from(rx);
+/// 处理node攒下的Ready: 要持久化的交给存储线程, 写入完成后才发消息(投票和确认必须在落盘之后),
+/// 再应用到状态机和执行读请求, 直到没有新的Ready. 发给客户端的回复交给等待者, 其他的转发到send函数处理
+#[allow(clippy::too_many_arguments)]
+async fn drive(
+    node: &mut Node,
+    store: &StoreWorker,
+    readies: &mut VecDeque<(Ready, bool)>,
+    state: &mut dyn State,
+    sessions: &mut Sessions,
+    pending: &mut HashMap<Vec<u8>, oneshot::Sender<Response>>,
+    tcp_out_tx: &mut mpsc::Sender<Message>,
+    metrics: &Metrics,
+) -> Result<()> {
     loop {
-        match async_std::net::TcpStream::connect(&addr).await {
-            Ok(mut socket) => {
-                println!("success connection: {}", &addr);
-                while let Some(msg) = rx.next().await {
-                    //TODO ser msg
-                    let enc_msg = bincode::serialize(&msg)?;
-                    socket.write_all(&enc_msg).await.unwrap();
-                    socket.flush().await;
+        //前面的写完了才能处理后面的, 后面的消息可能依赖前面写入的log
+        while readies.front().is_some_and(|(_, writing)| !writing) {
+            let (ready, _) = readies.pop_front().unwrap();
+            for msg in ready.messages {
+                if let Some(msg) = route(node, msg, pending, metrics) {
+                    metrics.outbound_queue.inc();
+                    tcp_out_tx.send(msg).await?;
                 }
             }
-            Err(e) => println!("{:?}", e),
+            let results = apply(node, state, sessions, ready.committed_entries);
+            metrics.sessions.set(sessions.len() as u64);
+            let reads = ready.reads.into_iter()
+                .map(|read| (read.id, state.query(read.query).map_err(|e| RequestError::State(e.to_string()))))
+                .collect();
+            node.advance(ready.checkpoint, results, reads)?;
+        }
+        if !node.has_ready() {
+            return Ok(());
+        }
+        let mut ready = node.ready()?;
+        let writing = match ready.take_write() {
+            Some(write) => {
+                store.write(write)?;
+                true
+            }
+            None => false,
+        };
+        //leader的复制消息和写盘同时进行
+        for msg in std::mem::take(&mut ready.replicate_messages) {
+            if let Some(msg) = route(node, msg, pending, metrics) {
+                metrics.outbound_queue.inc();
+                tcp_out_tx.send(msg).await?;
+            }
+        }
+        readies.push_back((ready, writing));
+    }
+}
+
+/// 存储线程完成了一次写入, 对应最早一个还在写的Ready
+fn written(readies: &mut VecDeque<(Ready, bool)>) -> Result<()> {
+    match readies.iter_mut().find(|(_, writing)| *writing) {
+        Some((_, writing)) => {
+            *writing = false;
+            Ok(())
+        }
+        None => Err(anyhow::anyhow!("unexpected store completion")),
+    }
+}
+
+/// 把已提交的State命令应用到状态机, 返回每条的(index, 结果)
+fn apply(node: &Node, state: &mut dyn State, sessions: &mut Sessions, entries: Vec<Entry>) -> Vec<(u64, Response)> {
+    let mut results = vec![];
+    for entry in entries {
+        let index = entry.index;
+        let mut apply = |command| {
+            let result = state.apply(index, command).map_err(|e| RequestError::State(e.to_string()));
+            if let Err(e) = &result {
+                log::debug!("[node={} term={} role={}] apply entry {} failed: {}",
+                    node.id(), node.term(), node.role_name(), index, e);
+            }
+            result
+        };
+        let result = match entry.command {
+            Command::State(command) => apply(command),
+            Command::RegisterSession { time } => Ok(sessions.register(index, time).to_be_bytes().to_vec()),
+            //重复的命令不再应用, 返回上次的结果
+            Command::SessionState { client_id, seq, time, command } =>
+                sessions.apply(client_id, seq, time, || apply(command)),
+            Command::Noop | Command::Membership(_) => continue,
+        };
+        results.push((index, result));
+    }
+    results
+}
+
+/// event loop里node发出的消息: 给句柄的回复交给等待者, 其余的返回, 发往peer
+fn route(
+    node: &Node,
+    msg: Message,
+    pending: &mut HashMap<Vec<u8>, oneshot::Sender<Response>>,
+    metrics: &Metrics,
+) -> Option<Message> {
+    metrics.messages.inc(&["out", msg.event.kind()]);
+    match msg {
+        Message { to: Address::Client, event: Event::ClientResponse { id, response }, .. } => {
+            match pending.remove(&id) {
+                Some(tx) => { let _ = tx.send(response); }
+                None => log::debug!("[node={} term={} role={}] client response {:?}",
+                    node.id(), node.term(), node.role_name(), response),
+            }
+            None
+        }
+        msg => Some(msg),
+    }
+}
+
+/// 可以接受新请求时才从请求队列里取
+async fn next_call(request_rx: &mut mpsc::Receiver<Call>, accept: bool) -> Call {
+    if accept {
+        if let Some(call) = request_rx.next().await {
+            return call;
         }
-        std::thread::sleep(Duration::from_secs(5));
     }
+    future::pending().await
 }
 
-async fn connection_loop(out_rx: UnboundedSender<Message>, mut stream: TcpStream) -> Result<()> {
-    let mut buffer = [0; 1024];
+/// 等到deadline, None时一直等
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => runtime::sleep(deadline.saturating_duration_since(Instant::now())).await,
+        None => future::pending().await,
+    }
+}
+
+/// 接受下一个连接. accept出错(比如文件描述符用完)不结束监听, 记下来稍等后继续
+async fn accept(ctx: &LogContext, listener: &TcpListener, name: &str, metrics: &Metrics) -> (TcpStream, SocketAddr) {
     loop {
-        let nbytes = stream.read(&mut buffer).await?;
-        if nbytes == 0 {
-            return Ok(());
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                metrics.accept_errors.inc(&[name]);
+                log::warn!("[{}] accept on {} listener failed: {}, retrying in {:?}",
+                    ctx, name, e, metrics::ACCEPT_BACKOFF);
+                runtime::sleep(metrics::ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// 连接任务里的panic只结束这个任务, 转成错误返回, 不影响节点
+async fn isolate(task: impl Future<Output = Result<()>>, metrics: &Metrics) -> Result<()> {
+    match AssertUnwindSafe(task).catch_unwind().await {
+        Ok(r) => r,
+        Err(panic) => {
+            metrics.task_panics.inc();
+            let reason = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(anyhow::anyhow!("task panicked: {}", reason))
         }
-        let msg: Message = bincode::deserialize(&buffer[..nbytes])?;
+    }
+}
 
-        let mut out_rx = UnboundedSender::from(out_rx.clone());
-        //let m = Message { term: 11, from:Address::Local, to: Address::Peers, event: Event::None };
-        out_rx.unbounded_send(msg);
+fn client_request(id: Vec<u8>, request: Request) -> Message {
+    Message {
+        term: 0,
+        from: Address::Client,
+        to: Address::Local,
+        event: Event::ClientRequest { id, request },
     }
-    Ok(())
-}
\ No newline at end of file
+}
+
+/// 先握手确认对方身份, 之后节点之间的消息转给event loop, 消息的from以握手时的node id为准;
+/// 运维查询直接在这个连接上回复. event loop处理不过来时在这里等待, 不再读这个连接, 由TCP反压到对端
+async fn connection_loop(
+    mut out_rx: mpsc::Sender<Message>,
+    stream: TcpStream,
+    endpoint: Endpoint,
+    handle: RaftHandle,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    let (mut stream, from): (Stream, _) = match endpoint.accept(stream).await {
+        Ok((stream, Handshake { node_id: Some(id), .. })) => (stream, Address::Peer(id)),
+        Ok((stream, Handshake { node_id: None, .. })) => (stream, Address::Client),
+        Err(e) => {
+            metrics.handshake_failures.inc();
+            return Err(e);
+        }
+    };
+    loop {
+        //读不出完整的消息时, 后面的字节也没法再对齐到帧边界, 只能关闭连接
+        let mut msg = match read_frame::<_, Message>(&mut stream, MAX_FRAME_BYTES).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if e.is::<BadFrame>() {
+                    metrics.bad_frames.inc();
+                }
+                return Err(e);
+            }
+        };
+        msg.from = from.clone();
+        match msg.event {
+            Event::StatusRequest => {
+                let status = handle.status().await?;
+                write_frame(&mut stream, &Message {
+                    term: status.term,
+                    from: Address::Local,
+                    to: msg.from,
+                    event: Event::StatusResponse { status },
+                }).await?;
+            }
+            //客户端连接只能查询
+            _ if from == Address::Client =>
+                return Err(anyhow::anyhow!("unexpected {} from client connection", msg.event.kind())),
+            _ => {
+                metrics.inbound_queue.inc();
+                if let Err(e) = out_rx.send(msg).await {
+                    metrics.inbound_queue.dec();
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
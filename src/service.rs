@@ -0,0 +1,112 @@
+//! listen_client端口上一个客户端连接的处理, 协议见protocol模块
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::io::ReadHalf;
+use futures::stream::FuturesUnordered;
+use futures::{future, AsyncReadExt, FutureExt, SinkExt, StreamExt};
+
+use crate::handle::{session_id, RaftHandle};
+use crate::message::{Request, RequestError};
+use crate::metrics::Metrics;
+use crate::protocol::{ClientReply, ClientRequest, Operation, Reply};
+use crate::runtime::{self, TcpStream};
+use crate::tls::Stream;
+use crate::transport::{read_frame, write_frame, BadFrame, Endpoint, MAX_FRAME_BYTES};
+
+/// 一个连接上同时处理的请求数, 满了就先不读这个连接, 由TCP反压到客户端
+const MAX_INFLIGHT: usize = 256;
+
+/// 握手后读请求并发执行, 每个完成后马上回复. 客户端关闭连接或者发来坏的帧时, 回复完已经在处理的请求再关闭
+pub(crate) async fn client_connection(
+    stream: TcpStream,
+    endpoint: Endpoint,
+    handle: RaftHandle,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    let stream = match endpoint.accept(stream).await {
+        Ok((stream, _)) => stream,
+        Err(e) => {
+            metrics.handshake_failures.inc();
+            return Err(e);
+        }
+    };
+    metrics.client_connections.inc();
+    let r = serve_requests(stream, handle, &metrics).await;
+    metrics.client_connections.dec();
+    r
+}
+
+async fn serve_requests(stream: Stream, handle: RaftHandle, metrics: &Arc<Metrics>) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    //读帧不能被回复打断, 放在单独的任务里
+    let (request_tx, mut request_rx) = mpsc::channel(0);
+    let (task, reading) = read_requests(reader, request_tx, metrics.clone()).remote_handle();
+    runtime::spawn(task);
+
+    let mut inflight = FuturesUnordered::new();
+    let mut open = true;
+    while open || !inflight.is_empty() {
+        let accept = open && inflight.len() < MAX_INFLIGHT;
+        futures::select! {
+            request = next_request(&mut request_rx, accept).fuse() => match request {
+                Some(request) => inflight.push(execute(handle.clone(), request)),
+                None => open = false,
+            },
+            reply = inflight.select_next_some() => write_frame(&mut writer, &reply).await?,
+        }
+    }
+    reading.await
+}
+
+/// 可以接受新请求时才取, 连接读完了返回None
+async fn next_request(request_rx: &mut mpsc::Receiver<ClientRequest>, accept: bool) -> Option<ClientRequest> {
+    if accept {
+        return request_rx.next().await;
+    }
+    future::pending().await
+}
+
+async fn read_requests(
+    mut reader: ReadHalf<Stream>,
+    mut request_tx: mpsc::Sender<ClientRequest>,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    loop {
+        let request = match read_frame::<_, ClientRequest>(&mut reader, MAX_FRAME_BYTES).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if e.is::<BadFrame>() {
+                    metrics.bad_frames.inc();
+                }
+                return Err(e);
+            }
+        };
+        if request_tx.send(request).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn execute(handle: RaftHandle, request: ClientRequest) -> ClientReply {
+    let result = match request.op {
+        Operation::Propose(command) => handle.call(Request::Propose(command)).await.map(Reply::Value),
+        Operation::Read(query) => handle.call(Request::Read(query)).await.map(Reply::Value),
+        Operation::Status => match handle.status().await {
+            Ok(status) => Ok(Reply::Status(Box::new(status))),
+            Err(_) => Err(RequestError::Stopped),
+        },
+        Operation::TransferLeadership(target) =>
+            handle.call(Request::TransferLeadership(target)).await.map(|_| Reply::Done),
+        Operation::ChangeMembership(change) =>
+            handle.call(Request::ChangeMembership(change)).await.map(|_| Reply::Done),
+        Operation::RegisterSession =>
+            handle.call(Request::RegisterSession).await.and_then(|id| session_id(&id)).map(Reply::Session),
+        Operation::SessionPropose { client_id, seq, command } =>
+            handle.call(Request::SessionPropose { client_id, seq, command }).await.map(Reply::Value),
+    };
+    ClientReply { id: request.id, result }
+}
@@ -0,0 +1,199 @@
+//! 客户端会话, 保证带会话的命令只应用一次(论文6.3). 会话的注册和命令都写在log里, 各节点按同样的顺序应用,
+//! 会话表在各节点上都一样. 过期按log里leader写入的时间判断, 不看本地时钟, 所有节点在同一条log上过期同样的会话
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::log::log::{Command, Entry};
+use crate::message::{RequestError, Response};
+
+/// 会话表, 由应用log的一方维护
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    timeout: u64,
+    //log时间: 已应用的log里最大的time, 毫秒. leader换了时钟可能不一致, 只取最大值保证不倒退
+    clock: u64,
+    sessions: HashMap<u64, Session>,
+    //(最后活跃时间, 会话id), 按时间顺序过期
+    expiry: BTreeSet<(u64, u64)>,
+}
+
+#[derive(Debug)]
+struct Session {
+    //最后应用的seq和它的结果. 重启后从log恢复的会话没有结果
+    seq: u64,
+    response: Option<Response>,
+    active: u64,
+}
+
+impl Sessions {
+    /// timeout_ms是会话不活跃多久后过期, 所有节点必须相同
+    pub fn new(timeout_ms: u64) -> Sessions {
+        Sessions { timeout: timeout_ms, clock: 0, sessions: HashMap::new(), expiry: BTreeSet::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// 重启后用状态机已应用的log重建会话表. 命令的结果没法重算, 只恢复seq, 重复的请求不会再应用
+    pub fn restore<'a>(&mut self, entries: impl Iterator<Item = &'a Entry>) {
+        for entry in entries {
+            match &entry.command {
+                Command::RegisterSession { time } => {
+                    self.register(entry.index, *time);
+                }
+                Command::SessionState { client_id, seq, time, .. } => {
+                    if let Some(session) = self.touch(*client_id, *time) {
+                        if *seq > session.seq {
+                            session.seq = *seq;
+                            session.response = None;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// 应用index处的会话注册, 返回会话id
+    pub fn register(&mut self, index: u64, time: u64) -> u64 {
+        self.advance(time);
+        self.sessions.insert(index, Session { seq: 0, response: None, active: self.clock });
+        self.expiry.insert((self.clock, index));
+        index
+    }
+
+    /// 应用带会话的命令: 新的seq用apply执行并记下结果, 重复的seq返回记下的结果, 不再执行
+    pub fn apply(&mut self, client_id: u64, seq: u64, time: u64, apply: impl FnOnce() -> Response) -> Response {
+        let session = match self.touch(client_id, time) {
+            Some(session) => session,
+            None => return Err(RequestError::SessionExpired),
+        };
+        if seq == 0 {
+            return Err(RequestError::Rejected("seq starts from 1".to_string()));
+        }
+        if seq < session.seq {
+            return Err(RequestError::Rejected(format!("seq {} is older than the last applied seq {}", seq, session.seq)));
+        }
+        if seq == session.seq {
+            return match &session.response {
+                Some(response) => response.clone(),
+                None => Err(RequestError::Rejected(format!("seq {} was applied before restart, response is lost", seq))),
+            };
+        }
+        let response = apply();
+        session.seq = seq;
+        session.response = Some(response.clone());
+        response
+    }
+
+    /// 会话上又应用了一条time时写入的命令, 会话已经过期时返回None
+    fn touch(&mut self, client_id: u64, time: u64) -> Option<&mut Session> {
+        self.advance(time);
+        let session = self.sessions.get_mut(&client_id)?;
+        self.expiry.remove(&(session.active, client_id));
+        session.active = self.clock;
+        self.expiry.insert((session.active, client_id));
+        Some(session)
+    }
+
+    /// log时间推进到time, 过期不活跃的会话
+    fn advance(&mut self, time: u64) {
+        self.clock = self.clock.max(time);
+        while let Some(&(active, client_id)) = self.expiry.iter().next() {
+            if active.saturating_add(self.timeout) >= self.clock {
+                break;
+            }
+            self.expiry.remove(&(active, client_id));
+            self.sessions.remove(&client_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_entry(index: u64, client_id: u64, seq: u64, time: u64) -> Entry {
+        Entry { index, term: 1, command: Command::SessionState { client_id, seq, time, command: vec![seq as u8] } }
+    }
+
+    #[test]
+    fn duplicate_returns_cached_response() {
+        let mut sessions = Sessions::new(1000);
+        let id = sessions.register(1, 0);
+        let mut applied = 0;
+        assert_eq!(sessions.apply(id, 1, 10, || { applied += 1; Ok(b"a".to_vec()) }), Ok(b"a".to_vec()));
+        //重试同一个seq, 返回记下的结果, 不再执行
+        assert_eq!(sessions.apply(id, 1, 20, || { applied += 1; Ok(b"b".to_vec()) }), Ok(b"a".to_vec()));
+        assert_eq!(applied, 1);
+        //状态机的错误也记下
+        let failed = Err(RequestError::State("bad".to_string()));
+        assert_eq!(sessions.apply(id, 2, 30, || failed.clone()), failed);
+        assert_eq!(sessions.apply(id, 2, 40, || unreachable!()), failed);
+    }
+
+    #[test]
+    fn old_seq_rejected() {
+        let mut sessions = Sessions::new(1000);
+        let id = sessions.register(1, 0);
+        assert!(matches!(sessions.apply(id, 0, 0, || unreachable!()), Err(RequestError::Rejected(_))));
+        assert!(sessions.apply(id, 3, 0, || Ok(vec![])).is_ok());
+        assert!(matches!(sessions.apply(id, 2, 0, || unreachable!()), Err(RequestError::Rejected(_))));
+        assert_eq!(sessions.apply(7, 1, 0, || unreachable!()), Err(RequestError::SessionExpired));
+    }
+
+    #[test]
+    fn inactive_sessions_expire() {
+        let mut sessions = Sessions::new(1000);
+        let idle = sessions.register(1, 0);
+        let busy = sessions.register(2, 500);
+        //刚好timeout时还没过期
+        assert!(sessions.apply(busy, 1, 1000, || Ok(vec![])).is_ok());
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.apply(busy, 2, 1001, || Ok(vec![])).is_ok());
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions.apply(idle, 1, 1001, || unreachable!()), Err(RequestError::SessionExpired));
+        //log时间不倒退: 新leader的时钟慢了, 会话从已知最大的时间算起, 不会马上过期
+        let late = sessions.register(3, 100);
+        assert!(sessions.apply(late, 1, 1500, || Ok(vec![])).is_ok());
+        assert_eq!(sessions.len(), 2);
+    }
+
+    #[test]
+    fn restore_matches_applied() {
+        let entries = [
+            Entry { index: 1, term: 1, command: Command::RegisterSession { time: 0 } },
+            session_entry(2, 1, 1, 200),
+            session_entry(3, 1, 2, 300),
+            //重复的seq也在log里, 应用时不执行
+            session_entry(4, 1, 2, 400),
+            Entry { index: 5, term: 1, command: Command::RegisterSession { time: 600 } },
+            session_entry(6, 5, 1, 1500),
+        ];
+        let mut applied = Sessions::new(1000);
+        for entry in entries.iter() {
+            match &entry.command {
+                Command::RegisterSession { time } => { applied.register(entry.index, *time); }
+                Command::SessionState { client_id, seq, time, command } => {
+                    applied.apply(*client_id, *seq, *time, || Ok(command.clone())).unwrap();
+                }
+                _ => (),
+            }
+        }
+        let mut restored = Sessions::new(1000);
+        restored.restore(entries.iter());
+
+        //会话1在1500时已经过期, 两边一样
+        for sessions in [&mut applied, &mut restored] {
+            assert_eq!(sessions.len(), 1);
+            assert_eq!(sessions.apply(1, 3, 1500, || unreachable!()), Err(RequestError::SessionExpired));
+        }
+        //恢复的会话记得seq, 重复的请求不再执行, 只是没有结果
+        assert_eq!(applied.apply(5, 1, 1500, || unreachable!()), Ok(vec![1]));
+        assert!(matches!(restored.apply(5, 1, 1500, || unreachable!()), Err(RequestError::Rejected(_))));
+        for sessions in [&mut applied, &mut restored] {
+            assert_eq!(sessions.apply(5, 2, 1600, || Ok(vec![2])), Ok(vec![2]));
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt::Debug;
+
+use anyhow::Result;
+
+/// 状态机: 按log顺序应用已提交的命令
+pub trait State: Debug + Send {
+    /// 已应用到状态机的最后一条log的index, 重启时从这里继续应用
+    fn applied_index(&self) -> u64;
+
+    /// 应用index处的命令, 返回值会回复给提交该命令的客户端.
+    /// 返回Err表示命令本身执行失败(对所有节点都一样), 不影响raft继续运行
+    fn apply(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// 只读查询, 不改变状态
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// 什么也不做的状态机, 命令和查询都原样返回. 用于测试, 或者只想看复制过程的时候
+#[derive(Debug, Default)]
+pub struct Echo {
+    applied_index: u64,
+}
+
+impl State for Echo {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn apply(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        self.applied_index = index;
+        Ok(command)
+    }
+
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(query)
+    }
+}
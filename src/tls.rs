@@ -0,0 +1,207 @@
+//! 节点之间和客户端连接的TLS. 双向认证: 双方都要出示配置的CA签发的证书;
+//! 节点id不一定是合法的域名, 不用rustls的主机名检查, 握手时单独检查证书的subject CN或SAN里有没有对方的节点id
+
+use std::convert::TryFrom;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use futures::{AsyncRead, AsyncWrite};
+use futures_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ParsedCertificate, WebPkiClientVerifier};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use x509_parser::extensions::GeneralName;
+
+use crate::conf::TlsConfig;
+use crate::runtime::TcpStream;
+
+/// 加载好的TLS配置, 用来建立和接受连接
+#[derive(Clone)]
+pub(crate) struct Tls {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Tls")
+    }
+}
+
+impl Tls {
+    pub fn load(conf: &TlsConfig) -> Result<Tls> {
+        let certs = CertificateDer::pem_file_iter(&conf.cert)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| anyhow::anyhow!("read certificate {}: {}", conf.cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(&conf.key)
+            .map_err(|e| anyhow::anyhow!("read private key {}: {}", conf.key, e))?;
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_file_iter(&conf.ca).map_err(|e| anyhow::anyhow!("read CA {}: {}", conf.ca, e))? {
+            roots.add(ca.map_err(|e| anyhow::anyhow!("read CA {}: {}", conf.ca, e))?)?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(CaVerifier { roots, provider }))
+            .with_client_auth_cert(certs, key)?;
+        Ok(Tls {
+            connector: TlsConnector::from(Arc::new(client)),
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+        })
+    }
+}
+
+/// 只验证对方证书是CA签发的, 身份由握手时的节点id检查
+#[derive(Debug)]
+struct CaVerifier {
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for CaVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        rustls::client::verify_server_cert_signed_by_trust_anchor(
+            &cert, &self.roots, intermediates, now, self.provider.signature_verification_algorithms.all,
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// 一个连接, 配置了TLS时是加密的
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    /// 连到addr的连接, 配置了TLS时做TLS握手
+    pub async fn connect(stream: TcpStream, addr: &str, tls: Option<&Tls>) -> Result<Stream> {
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return Ok(Stream::Plain(stream)),
+        };
+        //不检查主机名, 有SNI也无妨, 取地址的主机部分
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host).trim_matches(|c| c == '[' || c == ']');
+        let name = ServerName::try_from(host.to_string()).unwrap_or_else(|_| ServerName::try_from("iraft").unwrap());
+        let stream = tls.connector.connect(name, stream).await?;
+        Ok(Stream::Tls(Box::new(TlsStream::Client(stream))))
+    }
+
+    /// 接受的连接, 配置了TLS时做TLS握手
+    pub async fn accept(stream: TcpStream, tls: Option<&Tls>) -> Result<Stream> {
+        match tls {
+            Some(tls) => Ok(Stream::Tls(Box::new(TlsStream::Server(tls.acceptor.accept(stream).await?)))),
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    /// TLS连接上对方证书的subject CN或SAN要有node_id; 明文连接不检查
+    pub fn verify_node(&self, node_id: &str) -> std::result::Result<(), String> {
+        let stream = match self {
+            Stream::Plain(_) => return Ok(()),
+            Stream::Tls(stream) => stream,
+        };
+        let cert = match stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+            Some(cert) => cert,
+            None => return Err("no peer certificate".to_string()),
+        };
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).map_err(|e| format!("bad peer certificate: {}", e))?;
+        let cn = cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).any(|cn| cn == node_id);
+        let san = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san.value.general_names.iter().any(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => *name == node_id,
+                _ => false,
+            }),
+            _ => false,
+        };
+        if cn || san {
+            Ok(())
+        } else {
+            Err(format!("certificate is not issued for node {}", node_id))
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
@@ -1 +1,193 @@
+use std::io::IoSlice;
+use std::time::Duration;
 
+use anyhow::Result;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::runtime::{self, TcpStream};
+use crate::tls::{Stream, Tls};
+
+/// 协议版本, 帧格式或消息有不兼容的改动时加一
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+/// 连接建立后等待握手的时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 一帧最多的字节数, 长度前缀超过它的帧不读, 直接关闭连接; 发送方也不发出超过它的帧
+pub(crate) const MAX_FRAME_BYTES: usize = 64 << 20;
+/// 握手时对方还没认证, 帧的上限小得多
+const MAX_HANDSHAKE_BYTES: usize = 4 << 10;
+
+/// 对端发来的帧超长或者无法解码. 帧边界已经不可信, 连接要关掉
+#[derive(Debug)]
+pub(crate) struct BadFrame(String);
+
+impl std::fmt::Display for BadFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad frame: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadFrame {}
+
+/// 连接建立后双方先交换的第一帧. 节点之间的连接带上cluster_id和node_id;
+/// 运维工具等客户端不带node_id, 可以不带cluster_id. node_id只有在TLS下才和证书核对, 明文时不可信
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Handshake {
+    pub version: u32,
+    pub cluster_id: Option<String>,
+    pub node_id: Option<String>,
+}
+
+/// 接受方对握手的回复: 自己的握手, 或者拒绝的原因
+type HandshakeReply = std::result::Result<Handshake, String>;
+
+impl Handshake {
+    pub fn node(cluster_id: &str, node_id: &str) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            cluster_id: Some(cluster_id.to_string()),
+            node_id: Some(node_id.to_string()),
+        }
+    }
+
+    pub fn client() -> Handshake {
+        Handshake { version: PROTOCOL_VERSION, cluster_id: None, node_id: None }
+    }
+
+    /// 对方的握手能不能和自己通信
+    fn check(&self, theirs: &Handshake) -> std::result::Result<(), String> {
+        if theirs.version != self.version {
+            return Err(format!("protocol version {} does not match {}", theirs.version, self.version));
+        }
+        //节点之间必须是同一个集群, 客户端没带cluster_id时不检查
+        let checked = self.cluster_id.is_some() && (theirs.node_id.is_some() || theirs.cluster_id.is_some());
+        if checked && theirs.cluster_id != self.cluster_id {
+            return Err(format!("cluster id {:?} does not match {:?}", theirs.cluster_id, self.cluster_id));
+        }
+        if theirs.node_id.is_some() && theirs.node_id == self.node_id {
+            return Err(format!("node id {:?} is our own", theirs.node_id));
+        }
+        Ok(())
+    }
+}
+
+/// 本节点建立和接受连接的方式: 配置了TLS时先做TLS握手, 再交换Handshake.
+/// TLS连接上对方声明的node id必须和它的证书一致
+#[derive(Clone, Debug)]
+pub(crate) struct Endpoint {
+    pub handshake: Handshake,
+    pub tls: Option<Tls>,
+}
+
+impl Endpoint {
+    pub fn new(handshake: Handshake, tls: Option<Tls>) -> Endpoint {
+        Endpoint { handshake, tls }
+    }
+
+    /// 主动连到addr的连接: 发出握手, 检查对方的回复. expect_node是期望连上的节点id
+    pub async fn connect(&self, stream: TcpStream, addr: &str, expect_node: Option<&str>) -> Result<(Stream, Handshake)> {
+        let mut stream = runtime::timeout(HANDSHAKE_TIMEOUT, Stream::connect(stream, addr, self.tls.as_ref())).await??;
+        write_frame(&mut stream, &self.handshake).await?;
+        let reply = runtime::timeout(HANDSHAKE_TIMEOUT, read_frame::<_, HandshakeReply>(&mut stream, MAX_HANDSHAKE_BYTES)).await??;
+        let theirs = match reply {
+            Some(Ok(theirs)) => theirs,
+            Some(Err(reason)) => return Err(anyhow::anyhow!("handshake rejected: {}", reason)),
+            None => return Err(anyhow::anyhow!("connection closed during handshake")),
+        };
+        self.handshake.check(&theirs).map_err(|e| anyhow::anyhow!("handshake failed: {}", e))?;
+        if let Some(expect) = expect_node {
+            if theirs.node_id.as_deref() != Some(expect) {
+                return Err(anyhow::anyhow!("handshake failed: expected node {}, found {:?}", expect, theirs.node_id));
+            }
+            stream.verify_node(expect).map_err(|e| anyhow::anyhow!("handshake failed: {}", e))?;
+        }
+        Ok((stream, theirs))
+    }
+
+    /// 接受连接: 读对方的握手, 检查后回复. 不通过时告诉对方原因并返回错误
+    pub async fn accept(&self, stream: TcpStream) -> Result<(Stream, Handshake)> {
+        let mut stream = runtime::timeout(HANDSHAKE_TIMEOUT, Stream::accept(stream, self.tls.as_ref())).await??;
+        let theirs = match runtime::timeout(HANDSHAKE_TIMEOUT, read_frame::<_, Handshake>(&mut stream, MAX_HANDSHAKE_BYTES)).await?? {
+            Some(theirs) => theirs,
+            None => return Err(anyhow::anyhow!("connection closed during handshake")),
+        };
+        //客户端的证书只要求是CA签发的, 自称节点的要和证书一致
+        let checked = self.handshake.check(&theirs).and_then(|()| match &theirs.node_id {
+            Some(id) => stream.verify_node(id),
+            None => Ok(()),
+        });
+        if let Err(reason) = checked {
+            write_frame(&mut stream, &HandshakeReply::Err(reason.clone())).await?;
+            return Err(anyhow::anyhow!("handshake rejected: {}", reason));
+        }
+        write_frame(&mut stream, &HandshakeReply::Ok(self.handshake.clone())).await?;
+        Ok((stream, theirs))
+    }
+}
+
+/// 一帧: 4字节大端长度 + bincode编码的值
+pub(crate) fn encode_frame<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let body = bincode::serialize(value)?;
+    if body.len() > MAX_FRAME_BYTES {
+        return Err(anyhow::anyhow!("frame of {} bytes exceeds limit {}", body.len(), MAX_FRAME_BYTES));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// 解码一帧的内容. 和bincode::serialize的格式相同, 但读取的字节数不超过输入长度,
+/// 内部的长度前缀再大也只会报错, 不会按它分配内存; 多余的字节也算错误
+pub(crate) fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(bytes.len() as u64)
+        .deserialize(bytes)
+        .map_err(|e| BadFrame(e.to_string()).into())
+}
+
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin, T: Serialize>(w: &mut W, value: &T) -> Result<()> {
+    w.write_all(&encode_frame(value)?).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// 连续写多帧, 用vectored写减少系统调用, 都写完后flush一次
+pub(crate) async fn write_frames<W: AsyncWrite + Unpin>(w: &mut W, frames: &[Vec<u8>]) -> Result<()> {
+    let mut slices: Vec<IoSlice> = frames.iter().map(|f| IoSlice::new(f)).collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let n = w.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    w.flush().await?;
+    Ok(())
+}
+
+/// 读一帧, 对端正常关闭时返回None. 帧长超过max或者内容无法解码时返回BadFrame
+pub(crate) async fn read_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(r: &mut R, max: usize) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len).await {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(BadFrame(format!("frame of {} bytes exceeds limit {}", len, max)).into());
+    }
+    //按实际收到的数据增长, 只发长度前缀的连接占不了多少内存
+    let mut buffer = Vec::with_capacity(len.min(64 << 10));
+    (&mut *r).take(len as u64).read_to_end(&mut buffer).await?;
+    if buffer.len() < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(decode(&buffer)?))
+}
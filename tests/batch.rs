@@ -0,0 +1,73 @@
+//! 请求合并: 一个时间窗口内到达的提议作为一批交给节点, 各自提交, 各自回复
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use futures::future;
+use iraft::conf::{BatchConfig, Config};
+use iraft::metrics::Metrics;
+use iraft::runtime;
+
+/// 交给节点的请求批数
+fn batches(metrics: &Metrics) -> u64 {
+    let rendered = metrics.render();
+    let line = rendered.lines().find(|l| l.starts_with("iraft_request_batch_size_count ")).unwrap();
+    line.rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn proposals_in_one_window() {
+    runtime::block_on(async {
+        let conf = Config {
+            batch: BatchConfig { max_size: 64, max_delay_us: 200_000 },
+            tick_ms: 50,
+            ..common::conf("1", &[], 19700)
+        };
+        let server = common::server(conf).await;
+        let metrics = server.metrics();
+        let handles = vec![server.start()];
+        common::wait_leader(&handles).await;
+        let before = handles[0].status().await.unwrap();
+        let batched = batches(&metrics);
+
+        let n = 8u8;
+        let replies = future::join_all((0..n).map(|i| handles[0].propose(vec![i]))).await;
+        let replies: Vec<Vec<u8>> = replies.into_iter().map(Result::unwrap).collect();
+        assert_eq!(replies, (0..n).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(batches(&metrics), batched + 1);
+        //每个提议一条log
+        let status = handles[0].status().await.unwrap();
+        assert_eq!(status.last_index, before.last_index + n as u64);
+        assert_eq!(status.applied_index, status.last_index);
+
+        handles[0].shutdown(false).await.unwrap();
+    });
+}
+
+#[test]
+fn window_does_not_block_event_loop() {
+    runtime::block_on(async {
+        let conf = Config {
+            batch: BatchConfig { max_size: 64, max_delay_us: 1_000_000 },
+            tick_ms: 50,
+            ..common::conf("1", &[], 19710)
+        };
+        let handles = vec![common::server(conf).await.start()];
+        common::wait_leader(&handles).await;
+
+        //提议在窗口里等着, 节点照常回答状态查询
+        let proposal = handles[0].propose(b"x".to_vec());
+        let status = async {
+            runtime::sleep(Duration::from_millis(100)).await;
+            let start = Instant::now();
+            handles[0].status().await.unwrap();
+            start.elapsed()
+        };
+        let (reply, elapsed) = future::join(proposal, status).await;
+        assert_eq!(reply.unwrap(), b"x");
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+        handles[0].shutdown(false).await.unwrap();
+    });
+}
@@ -0,0 +1,56 @@
+//! 客户端库: 查询状态, 没有leader时在截止时间内重试后超时, 连不上的节点
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use iraft::client::{BlockingClient, Client, ClientConfig, ClientError};
+use iraft::conf::Config;
+use iraft::runtime;
+
+fn client_conf(seeds: &[&str]) -> ClientConfig {
+    ClientConfig {
+        seeds: seeds.iter().map(|s| s.to_string()).collect(),
+        timeout: Duration::from_secs(1),
+        connect_timeout: Duration::from_millis(500),
+        retry_interval: Duration::from_millis(50),
+        ..ClientConfig::default()
+    }
+}
+
+#[test]
+fn no_leader() {
+    runtime::block_on(async {
+        let conf = Config { listen_client: Some(common::addr(19042)), ..common::leaderless(19041, 19043) };
+        let handle = common::start(conf).await;
+
+        let client = Client::new(client_conf(&["127.0.0.1:19042"])).unwrap();
+        assert_eq!(client.status("127.0.0.1:19042").await.unwrap().id, "1");
+
+        //还没选出leader, 一直重试到截止时间
+        let start = Instant::now();
+        match client.read_by(b"x".to_vec(), start + Duration::from_millis(500)).await {
+            Err(ClientError::Timeout { last: Some(last) }) => assert!(last.contains("leader"), "{}", last),
+            r => panic!("unexpected read result {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(matches!(client.propose(b"x".to_vec()).await, Err(ClientError::Timeout { .. })));
+        assert_eq!(client.leader(), None);
+
+        handle.shutdown(false).await.unwrap();
+    });
+}
+
+#[test]
+fn unreachable_seeds() {
+    assert!(matches!(Client::new(client_conf(&[])), Err(ClientError::Config(_))));
+
+    let client = BlockingClient::new(client_conf(&["127.0.0.1:1"])).unwrap();
+    let start = Instant::now();
+    match client.read(b"x".to_vec()) {
+        Err(ClientError::Timeout { last: Some(last) }) => assert!(last.contains("leader"), "{}", last),
+        r => panic!("unexpected read result {:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert!(matches!(client.status("127.0.0.1:1"), Err(ClientError::Connection(_))));
+}
@@ -0,0 +1,75 @@
+//! listen_client端口上的帧协议: 握手, 带id的请求和回复, 同一连接上的并发请求
+
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use iraft::conf::Config;
+use iraft::message::RequestError;
+use iraft::protocol::{ClientReply, ClientRequest, Operation, Reply};
+use iraft::runtime::{self, TcpStream};
+
+/// 客户端的握手帧: version=1, cluster_id=None, node_id=None
+const CLIENT_HANDSHAKE: [u8; 10] = [0, 0, 0, 6, 1, 0, 0, 0, 0, 0];
+
+async fn read_body(stream: &mut TcpStream) -> Vec<u8> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await.unwrap();
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body).await.unwrap();
+    body
+}
+
+async fn connect(addr: &str) -> TcpStream {
+    let mut stream = runtime::connect(addr).await.unwrap();
+    stream.write_all(&CLIENT_HANDSHAKE).await.unwrap();
+    //回复是Ok(Handshake)
+    assert_eq!(&read_body(&mut stream).await[..4], &[0, 0, 0, 0]);
+    stream
+}
+
+async fn send(stream: &mut TcpStream, id: u64, op: Operation) {
+    let body = ClientRequest { id, op }.encode().unwrap();
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(&body).await.unwrap();
+}
+
+#[test]
+fn requests_on_one_connection() {
+    runtime::block_on(async {
+        let conf = Config { listen_client: Some(common::addr(19032)), ..common::leaderless(19031, 19033) };
+        let server = common::server(conf).await;
+        let metrics = server.metrics();
+        let handle = server.start();
+        runtime::sleep(Duration::from_millis(200)).await;
+
+        //一次发出多个请求, 回复按id对应
+        let mut stream = connect("127.0.0.1:19032").await;
+        send(&mut stream, 7, Operation::Status).await;
+        send(&mut stream, 8, Operation::Propose(b"x".to_vec())).await;
+        send(&mut stream, 9, Operation::Read(b"y".to_vec())).await;
+        let mut replies = HashMap::new();
+        for _ in 0..3 {
+            let reply = ClientReply::decode(&read_body(&mut stream).await).unwrap();
+            replies.insert(reply.id, reply.result);
+        }
+        match replies.remove(&7) {
+            Some(Ok(Reply::Status(status))) => assert_eq!(status.id, "1"),
+            r => panic!("unexpected status reply {:?}", r),
+        }
+        //还没选出leader, 错误带类型
+        assert_eq!(replies.remove(&8).unwrap().unwrap_err(), RequestError::NotLeader { leader: None });
+        assert_eq!(replies.remove(&9).unwrap().unwrap_err(), RequestError::NotLeader { leader: None });
+        assert_eq!(metrics.client_connections.get(), 1);
+
+        //坏的帧关闭连接
+        stream.write_all(&[0, 0, 0, 2, 0xff, 0xff]).await.unwrap();
+        let mut buffer = [0; 16];
+        assert!(matches!(runtime::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await, Ok(Ok(0)) | Ok(Err(_))));
+        assert_eq!(metrics.bad_frames.get(), 1);
+
+        handle.shutdown(false).await.unwrap();
+    });
+}
@@ -0,0 +1,83 @@
+//! 三节点集群: 选举, 复制, 转移leader后停止
+
+mod common;
+
+use std::time::Duration;
+
+use iraft::handle::RaftHandle;
+use iraft::runtime;
+
+/// 等所有节点都应用到index
+async fn wait_applied(handles: &[&RaftHandle], index: u64) {
+    for _ in 0..100 {
+        let mut applied = true;
+        for handle in handles {
+            applied &= handle.status().await.unwrap().applied_index >= index;
+        }
+        if applied {
+            return;
+        }
+        runtime::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("log not applied to {} on all nodes", index);
+}
+
+#[test]
+fn election_and_replication() {
+    runtime::block_on(async {
+        let handles = common::start_all(common::cluster(3, 19600)).await;
+        let l = common::wait_leader(&handles).await;
+        let leader = handles[l].status().await.unwrap();
+        //其他节点都认这个leader
+        for handle in handles.iter() {
+            let status = handle.status().await.unwrap();
+            assert_eq!((status.term, status.leader.as_deref()), (leader.term, Some(leader.id.as_str())));
+        }
+
+        for i in 0..10u8 {
+            assert_eq!(handles[l].propose(vec![i]).await.unwrap(), vec![i]);
+        }
+        assert_eq!(handles[l].read(b"q".to_vec()).await.unwrap(), b"q");
+        let status = handles[l].status().await.unwrap();
+        assert_eq!(status.commit_index, leader.commit_index + 10);
+        wait_applied(&handles.iter().collect::<Vec<_>>(), status.commit_index).await;
+        //leader看到每个peer都复制到了最新
+        let status = handles[l].status().await.unwrap();
+        assert_eq!(status.peers.len(), 2);
+        assert!(status.peers.iter().all(|p| p.match_index == status.last_index));
+
+        for handle in handles {
+            handle.shutdown(false).await.unwrap();
+        }
+    });
+}
+
+#[test]
+fn shutdown_transfers_leadership() {
+    runtime::block_on(async {
+        let handles = common::start_all(common::cluster(3, 19620)).await;
+        let l = common::wait_leader(&handles).await;
+        for i in 0..5u8 {
+            handles[l].propose(vec![i]).await.unwrap();
+        }
+        let before = handles[l].status().await.unwrap();
+
+        //停止前把leader交给别人, 最终状态里已经不是leader了
+        let last = handles[l].shutdown(true).await.unwrap();
+        assert_eq!(last.role, "follower");
+        assert!(last.term > before.term);
+        assert!(handles[l].status().await.is_err());
+
+        //新leader马上就有, 不用等选举超时; 提交了自己任期的log后, 之前提交的log都在
+        let rest: Vec<RaftHandle> = handles.iter().enumerate().filter(|(i, _)| *i != l).map(|(_, h)| h.clone()).collect();
+        let n = common::wait_leader(&rest).await;
+        assert_eq!(rest[n].propose(b"after".to_vec()).await.unwrap(), b"after");
+        let status = rest[n].status().await.unwrap();
+        assert_eq!(status.term, last.term);
+        assert!(status.commit_index > before.commit_index);
+
+        for handle in rest {
+            handle.shutdown(false).await.unwrap();
+        }
+    });
+}
@@ -0,0 +1,90 @@
+//! 集成测试共用的节点配置和启动方法. 每个测试文件只用到其中一部分
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use iraft::conf::Config;
+use iraft::handle::RaftHandle;
+use iraft::runtime;
+use iraft::server::RaftServer;
+use iraft::state::Echo;
+
+pub fn addr(port: u16) -> String {
+    format!("127.0.0.1:{}", port)
+}
+
+/// 在raft_port上监听的节点, peers是(id, raft端口)
+pub fn conf(id: &str, peers: &[(&str, u16)], raft_port: u16) -> Config {
+    Config {
+        id: id.to_string(),
+        cluster_id: "test".to_string(),
+        peers: peers.iter().map(|(id, port)| (id.to_string(), addr(*port))).collect(),
+        listen_raft: addr(raft_port),
+        ..Config::default()
+    }
+}
+
+/// 永远选不出leader的节点: 唯一的peer在absent_port上, 没有进程监听, 拿不到多数票
+pub fn leaderless(raft_port: u16, absent_port: u16) -> Config {
+    conf("1", &[("2", absent_port)], raft_port)
+}
+
+/// 一个集群里各节点的配置: 节点id是1..=n, 节点i的raft端口是base+i, 客户端端口是base+10+i.
+/// tick很短, 几百毫秒就能选出leader
+pub fn cluster(n: u16, base: u16) -> Vec<Config> {
+    (1..=n).map(|i| {
+        let peers: Vec<(String, u16)> = (1..=n).filter(|p| *p != i).map(|p| (p.to_string(), base + p)).collect();
+        let peers: Vec<(&str, u16)> = peers.iter().map(|(id, port)| (id.as_str(), *port)).collect();
+        Config {
+            listen_client: Some(addr(base + 10 + i)),
+            tick_ms: 50,
+            ..conf(&i.to_string(), &peers, base + i)
+        }
+    }).collect()
+}
+
+pub async fn server(conf: Config) -> RaftServer {
+    RaftServer::new(conf, Box::new(Echo::default())).await.unwrap()
+}
+
+/// 在后台启动节点, 等监听端口就绪
+pub async fn start(conf: Config) -> RaftHandle {
+    let handle = server(conf).await.start();
+    runtime::sleep(Duration::from_millis(200)).await;
+    handle
+}
+
+/// 启动集群里的所有节点
+pub async fn start_all(confs: Vec<Config>) -> Vec<RaftHandle> {
+    let mut handles = vec![];
+    for conf in confs {
+        handles.push(server(conf).await.start());
+    }
+    handles
+}
+
+/// 等到cond成立, 最多等5秒
+pub async fn wait(cond: impl Fn() -> bool) -> bool {
+    for _ in 0..50 {
+        if cond() {
+            return true;
+        }
+        runtime::sleep(Duration::from_millis(100)).await;
+    }
+    cond()
+}
+
+/// 等handles里有节点当选并且有已提交的log, 返回它的下标. 10秒内没选出来时panic
+pub async fn wait_leader(handles: &[RaftHandle]) -> usize {
+    for _ in 0..200 {
+        for (i, handle) in handles.iter().enumerate() {
+            if let Ok(status) = handle.status().await {
+                if status.role == "leader" && status.commit_index > 0 && status.applied_index == status.commit_index {
+                    return i;
+                }
+            }
+        }
+        runtime::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no leader elected");
+}
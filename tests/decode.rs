@@ -0,0 +1,181 @@
+//! 解码不可信的网络输入: 随机字节, 改坏的合法消息, 伪造的长度前缀
+
+mod common;
+
+use std::time::Duration;
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use iraft::handle::fetch_status;
+use iraft::log::log::{Command, ConfigChange, Entry};
+use iraft::message::{Address, Event, Message, Request};
+use iraft::runtime;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+fn samples() -> Vec<Message> {
+    let message = |event| Message { term: 3, from: Address::Peer("1".to_string()), to: Address::Peers, event };
+    vec![
+        message(Event::Heartbeat { commit_index: 10, commit_term: 2, read_seq: 7 }),
+        message(Event::ReplicateEntries {
+            base_index: 4,
+            base_term: 2,
+            entries: vec![
+                Entry { index: 5, term: 3, command: Command::State(vec![7; 16]) },
+                Entry { index: 6, term: 3, command: Command::Noop },
+                Entry {
+                    index: 7,
+                    term: 3,
+                    command: Command::Membership(ConfigChange::AddPeer { id: "4".to_string(), addr: "127.0.0.1:4".to_string() }),
+                },
+                Entry { index: 8, term: 3, command: Command::RegisterSession { time: 1_700_000_000_000 } },
+                Entry {
+                    index: 9,
+                    term: 3,
+                    command: Command::SessionState { client_id: 8, seq: 1, time: 1_700_000_000_001, command: vec![9; 8] },
+                },
+            ],
+        }),
+        message(Event::RejectEntries { base_index: 9, last_index: 6 }),
+        message(Event::ClientRequest { id: vec![1, 2, 3], request: Request::Propose(b"set x 1".to_vec()) }),
+    ]
+}
+
+/// 在bytes里找到pattern第一次出现的位置
+fn find(bytes: &[u8], pattern: &[u8]) -> usize {
+    bytes.windows(pattern.len()).position(|w| w == pattern).unwrap()
+}
+
+#[test]
+fn roundtrip() {
+    for msg in samples() {
+        let bytes = msg.encode().unwrap();
+        assert_eq!(Message::decode(&bytes).unwrap().encode().unwrap(), bytes);
+        //多余的字节说明帧边界不对
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Message::decode(&trailing).is_err());
+    }
+}
+
+#[test]
+fn random_bytes() {
+    let mut rng = StdRng::seed_from_u64(45);
+    for _ in 0..20000 {
+        let len = rng.gen_range(0..256);
+        let bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        let _ = Message::decode(&bytes);
+    }
+}
+
+#[test]
+fn mutated_messages() {
+    let mut rng = StdRng::seed_from_u64(46);
+    let samples: Vec<Vec<u8>> = samples().iter().map(|msg| msg.encode().unwrap()).collect();
+    for _ in 0..20000 {
+        let mut bytes = samples[rng.gen_range(0..samples.len())].clone();
+        for _ in 0..rng.gen_range(1..4) {
+            match rng.gen_range(0..4) {
+                0 => {
+                    let i = rng.gen_range(0..bytes.len());
+                    bytes[i] = rng.gen();
+                }
+                1 => {
+                    let i = rng.gen_range(0..bytes.len());
+                    bytes[i] ^= 1 << rng.gen_range(0..8);
+                }
+                2 => bytes.truncate(rng.gen_range(0..bytes.len())),
+                _ => {
+                    //长度前缀最容易被改成很大的数
+                    let i = rng.gen_range(0..bytes.len());
+                    let end = (i + 8).min(bytes.len());
+                    bytes[i..end].fill(0xff);
+                }
+            }
+            if bytes.is_empty() {
+                break;
+            }
+        }
+        let _ = Message::decode(&bytes);
+    }
+}
+
+#[test]
+fn huge_length_prefix() {
+    let msg = Message {
+        term: 1,
+        from: Address::Peer("1".to_string()),
+        to: Address::Peers,
+        event: Event::ReplicateEntries {
+            base_index: 0,
+            base_term: 0,
+            entries: vec![Entry { index: 1, term: 1, command: Command::State(vec![7; 16]) }],
+        },
+    };
+    let bytes = msg.encode().unwrap();
+
+    //命令的长度改成几乎2^64, 不能按它分配内存
+    let mut command = bytes.clone();
+    let i = find(&command, &[16, 0, 0, 0, 0, 0, 0, 0, 7]);
+    command[i..i + 8].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+    assert!(Message::decode(&command).is_err());
+
+    //log条数同理
+    let mut entries = bytes;
+    let i = find(&entries, &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+    entries[i..i + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    assert!(Message::decode(&entries).is_err());
+}
+
+/// 客户端的握手帧: version=1, cluster_id=None, node_id=None
+const CLIENT_HANDSHAKE: [u8; 10] = [0, 0, 0, 6, 1, 0, 0, 0, 0, 0];
+
+/// 握手后发出frame, 返回节点是否关闭了连接
+async fn send_after_handshake(addr: &str, frame: &[u8]) -> bool {
+    let mut stream = runtime::connect(addr).await.unwrap();
+    stream.write_all(&CLIENT_HANDSHAKE).await.unwrap();
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).await.unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply).await.unwrap();
+    //回复是Ok(Handshake)
+    assert_eq!(&reply[..4], &[0, 0, 0, 0]);
+
+    stream.write_all(frame).await.unwrap();
+    let mut buffer = [0; 16];
+    matches!(runtime::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await, Ok(Ok(0)) | Ok(Err(_)))
+}
+
+#[test]
+fn bad_frames_close_connection() {
+    runtime::block_on(async {
+        let server = common::server(common::conf("1", &[], 19021)).await;
+        let metrics = server.metrics();
+        let handle = server.start();
+        runtime::sleep(Duration::from_millis(200)).await;
+
+        //声明4GB的帧
+        assert!(send_after_handshake("127.0.0.1:19021", &[0xff, 0xff, 0xff, 0xff]).await);
+        assert_eq!(metrics.bad_frames.get(), 1);
+        //长度正常, 内容是乱码
+        assert!(send_after_handshake("127.0.0.1:19021", &[0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).await);
+        assert_eq!(metrics.bad_frames.get(), 2);
+
+        //握手时就发超长的帧, 算握手失败
+        let mut stream = runtime::connect("127.0.0.1:19021").await.unwrap();
+        stream.write_all(&[0, 0x10, 0, 0]).await.unwrap();
+        let mut buffer = [0; 16];
+        assert!(matches!(stream.read(&mut buffer).await, Ok(0) | Err(_)));
+        //连接在计数之前就关掉了
+        for _ in 0..50 {
+            if metrics.handshake_failures.get() > 0 {
+                break;
+            }
+            runtime::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(metrics.handshake_failures.get(), 1);
+
+        //节点照常服务
+        assert_eq!(fetch_status("127.0.0.1:19021", None).await.unwrap().id, "1");
+        handle.shutdown(false).await.unwrap();
+    });
+}
@@ -0,0 +1,52 @@
+//! 请求转发: follower把提议和读请求转给leader并转回回复, 没有leader时按转发超时失败
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use iraft::conf::{Config, ForwardConfig};
+use iraft::message::RequestError;
+use iraft::runtime;
+
+fn forward(timeout_ms: u64) -> ForwardConfig {
+    ForwardConfig { enabled: true, timeout_ms }
+}
+
+#[test]
+fn follower_forwards_to_leader() {
+    runtime::block_on(async {
+        let confs = common::cluster(3, 19680).into_iter()
+            .map(|conf| Config { forward: forward(5000), ..conf })
+            .collect();
+        let handles = common::start_all(confs).await;
+        let l = common::wait_leader(&handles).await;
+        let f = (l + 1) % handles.len();
+        let before = handles[l].status().await.unwrap().commit_index;
+
+        assert_eq!(handles[f].propose(b"x".to_vec()).await.unwrap(), b"x");
+        assert_eq!(handles[f].read(b"q".to_vec()).await.unwrap(), b"q");
+        //提议是在leader上提交的
+        assert_eq!(handles[l].status().await.unwrap().commit_index, before + 1);
+
+        for handle in handles {
+            handle.shutdown(false).await.unwrap();
+        }
+    });
+}
+
+#[test]
+fn forward_times_out_between_ticks() {
+    runtime::block_on(async {
+        //tick 10秒, 转发超时300毫秒, 不用等到下个tick就失败
+        let conf = Config { forward: forward(300), tick_ms: 10000, ..common::leaderless(19691, 19692) };
+        let handle = common::start(conf).await;
+
+        let start = Instant::now();
+        let e = handle.propose(b"x".to_vec()).await.unwrap_err();
+        assert_eq!(e.downcast_ref::<RequestError>(), Some(&RequestError::NotLeader { leader: None }));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        handle.shutdown(false).await.unwrap();
+    });
+}
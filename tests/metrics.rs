@@ -0,0 +1,42 @@
+//! 指标端口: 绑定失败时启动失败, 正常时提供 GET /metrics
+
+mod common;
+
+use std::time::Duration;
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use iraft::conf::Config;
+use iraft::runtime::{self, TcpListener};
+
+fn conf(raft_port: u16, metrics_port: u16) -> Config {
+    Config { listen_metrics: Some(common::addr(metrics_port)), ..common::conf("1", &[], raft_port) }
+}
+
+#[test]
+fn metrics_port_in_use() {
+    runtime::block_on(async {
+        let _taken = TcpListener::bind("127.0.0.1:19052").await.unwrap();
+        let server = common::server(conf(19051, 19052)).await;
+        let (_client_tx, client_rx) = futures::channel::mpsc::channel(1);
+        let e = runtime::timeout(Duration::from_secs(5), server.serve(client_rx)).await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("metrics"), "{}", e);
+    });
+}
+
+#[test]
+fn metrics_endpoint() {
+    runtime::block_on(async {
+        let server = common::server(conf(19053, 19054)).await;
+        let handle = server.start();
+        runtime::sleep(Duration::from_millis(200)).await;
+
+        let mut stream = runtime::connect("127.0.0.1:19054").await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("iraft_term"));
+
+        handle.shutdown(false).await.unwrap();
+    });
+}
@@ -0,0 +1,90 @@
+//! peer断线重连: follower停掉后leader标记断线, 重启后重新连上并追上log
+
+mod common;
+
+use std::time::Duration;
+
+use iraft::conf::{Config, StorageKind};
+use iraft::handle::RaftHandle;
+use iraft::runtime;
+
+/// 等leader看到peer的连接状态变成connected, 最多等10秒
+async fn wait_connected(leader: &RaftHandle, peer: &str, connected: bool) {
+    for _ in 0..200 {
+        let status = leader.status().await.unwrap();
+        if status.peers.iter().any(|p| p.id == peer && p.connected == connected) {
+            return;
+        }
+        runtime::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("peer {} never became connected={}", peer, connected);
+}
+
+/// 等某个leader和所有peer都连着并且复制到了最新, handles[f]应用到了index, 最多等10秒
+async fn wait_caught_up(handles: &[RaftHandle], f: usize, index: u64) {
+    for _ in 0..200 {
+        let mut caught_up = handles[f].status().await.unwrap().applied_index >= index;
+        let leader = common::wait_leader(handles).await;
+        let status = handles[leader].status().await.unwrap();
+        caught_up &= status.peers.iter().all(|p| p.connected && p.match_index == status.last_index);
+        if caught_up {
+            return;
+        }
+        runtime::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("node {} did not catch up to {}", f + 1, index);
+}
+
+/// 交给当前的leader提交, 返回它的下标
+async fn propose(handles: &[RaftHandle], command: Vec<u8>) -> usize {
+    loop {
+        let l = common::wait_leader(handles).await;
+        if handles[l].propose(command.clone()).await.is_ok() {
+            return l;
+        }
+    }
+}
+
+/// 重启后log和投票要还在, 用文件存储
+fn confs() -> Vec<Config> {
+    common::cluster(3, 19660).into_iter().map(|conf| {
+        let dir = std::env::temp_dir().join(format!("iraft-reconnect-{}-{}", conf.id, std::process::id()));
+        Config { storage: StorageKind::File, data_dir: dir.to_str().unwrap().to_string(), ..conf }
+    }).collect()
+}
+
+#[test]
+fn follower_reconnects_and_catches_up() {
+    runtime::block_on(async {
+        for conf in confs() {
+            let _ = std::fs::remove_dir_all(&conf.data_dir);
+            std::fs::create_dir_all(&conf.data_dir).unwrap();
+        }
+        let mut handles = common::start_all(confs()).await;
+        let l = common::wait_leader(&handles).await;
+        let f = (l + 1) % handles.len();
+        let peer = (f + 1).to_string();
+
+        handles[f].shutdown(false).await.unwrap();
+        //还有多数节点, 可以继续提交; 发给停掉的peer失败后leader标记它断线. 刚选出的leader可能马上被换掉, 提交失败时找新leader重试
+        let live: Vec<RaftHandle> = handles.iter().enumerate().filter(|(i, _)| *i != f).map(|(_, h)| h.clone()).collect();
+        let mut l = 0;
+        for i in 0..3u8 {
+            l = propose(&live, vec![i]).await;
+        }
+        wait_connected(&live[l], &peer, false).await;
+        let commit_index = live[l].status().await.unwrap().commit_index;
+
+        //过一会再重启, 这期间leader在退避重连. 重启的节点可能在leader重连上之前超时发起选举, 换了leader也要能追上
+        runtime::sleep(Duration::from_millis(500)).await;
+        handles[f] = common::start(confs().remove(f)).await;
+        wait_caught_up(&handles, f, commit_index).await;
+
+        for handle in handles {
+            handle.shutdown(false).await.unwrap();
+        }
+        for conf in confs() {
+            let _ = std::fs::remove_dir_all(conf.data_dir);
+        }
+    });
+}
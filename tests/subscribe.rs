@@ -0,0 +1,69 @@
+//! 订阅节点事件: 三节点选举和提交时, 各节点收到的事件按发生顺序排列
+
+mod common;
+
+use std::time::Duration;
+
+use iraft::notify::{NodeEvent, Notification, Subscription};
+use iraft::runtime;
+
+fn drain(subscription: &mut Subscription) -> Vec<Notification> {
+    let mut notifications = vec![];
+    while let Ok(Some(n)) = subscription.try_next() {
+        notifications.push(n);
+    }
+    notifications
+}
+
+fn position(events: &[Notification], f: impl Fn(&Notification) -> bool) -> usize {
+    events.iter().rposition(f).unwrap_or_else(|| panic!("event not found in {:?}", events))
+}
+
+#[test]
+fn events_in_order() {
+    runtime::block_on(async {
+        let mut handles = vec![];
+        let mut subscriptions = vec![];
+        for conf in common::cluster(3, 19640) {
+            let server = common::server(conf).await;
+            subscriptions.push(server.subscribe());
+            handles.push(server.start());
+        }
+        let l = common::wait_leader(&handles).await;
+        handles[l].propose(b"x".to_vec()).await.unwrap();
+        let status = handles[l].status().await.unwrap();
+        let (term, leader, index) = (status.term, status.id, status.commit_index);
+        //等followers从心跳里知道新的commit index
+        runtime::sleep(Duration::from_millis(300)).await;
+
+        for (i, subscription) in subscriptions.iter_mut().enumerate() {
+            let events = drain(subscription);
+            //任期和commit index只增不减
+            assert!(events.windows(2).all(|w| w[0].term <= w[1].term), "{:?}", events);
+            let commits: Vec<u64> = events.iter().filter_map(|n| match n.event {
+                NodeEvent::CommitAdvanced { commit_index } => Some(commit_index),
+                _ => None,
+            }).collect();
+            assert!(commits.windows(2).all(|w| w[0] < w[1]), "{:?}", commits);
+            //之后可能换了leader, 新leader的空log让commit index再往前走
+            assert!(commits.contains(&index), "{:?}", commits);
+
+            //先知道leader, 再看到它提交的log
+            let known = position(&events, |n| n.term == term && n.event == NodeEvent::LeaderChanged { leader: Some(leader.clone()) });
+            let committed = position(&events, |n| n.event == NodeEvent::CommitAdvanced { commit_index: index });
+            assert!(known < committed, "{:?}", events);
+
+            if i == l {
+                //当选的过程: 成为候选者, 进入新任期, 成为leader, 然后才是leader和commit的变化
+                let candidate = position(&events, |n| n.event == NodeEvent::RoleChanged { from: "follower", to: "candidate" });
+                let new_term = position(&events, |n| n.event == NodeEvent::TermChanged { term });
+                let elected = position(&events, |n| n.event == NodeEvent::RoleChanged { from: "candidate", to: "leader" });
+                assert!(candidate < new_term && new_term < elected && elected < known, "{:?}", events);
+            }
+        }
+
+        for handle in handles {
+            handle.shutdown(false).await.unwrap();
+        }
+    });
+}
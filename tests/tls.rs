@@ -0,0 +1,119 @@
+//! 用测试时生成的自签名证书检查raft端口的双向TLS
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use iraft::conf::{Config, TlsConfig};
+use iraft::handle::fetch_status;
+use iraft::metrics::Metrics;
+use iraft::runtime;
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair};
+
+/// 测试用的CA, 签发的证书写到dir下
+struct Ca {
+    dir: PathBuf,
+    name: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &str) -> Ca {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, format!("{} CA", name));
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        std::fs::write(dir.join(format!("{}-ca.pem", name)), cert.pem()).unwrap();
+        Ca { dir: dir.to_path_buf(), name: name.to_string(), issuer: Issuer::new(params, key) }
+    }
+
+    /// 签发subject CN为cn, SAN为sans的证书
+    fn issue(&self, file: &str, cn: &str, sans: &[&str]) -> TlsConfig {
+        let mut params = CertificateParams::new(sans.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.issuer).unwrap();
+        let path = |suffix: &str| self.dir.join(format!("{}-{}.pem", file, suffix)).to_str().unwrap().to_string();
+        std::fs::write(path("cert"), cert.pem()).unwrap();
+        std::fs::write(path("key"), key.serialize_pem()).unwrap();
+        TlsConfig {
+            cert: path("cert"),
+            key: path("key"),
+            ca: self.dir.join(format!("{}-ca.pem", self.name)).to_str().unwrap().to_string(),
+        }
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("iraft-tls-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn conf(id: &str, peers: &[(&str, u16)], port: u16, tls: TlsConfig) -> Config {
+    Config { tls: Some(tls), ..common::conf(id, peers, port) }
+}
+
+fn connected(metrics: &Metrics, peer: &str) -> bool {
+    metrics.peer_connected.get(&[peer]) == 1
+}
+
+#[test]
+fn peers_authenticate_each_other() {
+    runtime::block_on(async {
+        let dir = temp_dir("peers");
+        let ca = Ca::new(&dir, "cluster");
+        //节点id可以在CN里, 也可以在SAN里
+        let n1 = ca.issue("n1", "1", &[]);
+        let n2 = ca.issue("n2", "node two", &["2"]);
+        let s1 = common::server(conf("1", &[("2", 19002)], 19001, n1)).await;
+        let s2 = common::server(conf("2", &[("1", 19001)], 19002, n2)).await;
+        let (m1, m2) = (s1.metrics(), s2.metrics());
+        let (h1, h2) = (s1.start(), s2.start());
+
+        //节点一开始就认为peer是连着的, 等一会儿再看有没有断开
+        runtime::sleep(Duration::from_secs(1)).await;
+        assert!(connected(&m1, "2") && connected(&m2, "1"));
+        assert_eq!(m1.handshake_failures.get(), 0);
+        assert_eq!(m2.handshake_failures.get(), 0);
+
+        //运维工具用同一个CA签发的证书查询
+        let admin = ca.issue("admin", "admin", &[]);
+        let status = fetch_status("127.0.0.1:19001", Some(&admin)).await.unwrap();
+        assert_eq!(status.id, "1");
+        //明文连接和其他CA的证书都被拒绝
+        assert!(fetch_status("127.0.0.1:19001", None).await.is_err());
+        let other = Ca::new(&dir, "other").issue("stranger", "admin", &[]);
+        assert!(fetch_status("127.0.0.1:19001", Some(&other)).await.is_err());
+
+        h1.shutdown(false).await.unwrap();
+        h2.shutdown(false).await.unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+    });
+}
+
+#[test]
+fn peer_with_wrong_node_certificate_is_rejected() {
+    runtime::block_on(async {
+        let dir = temp_dir("wrong-node");
+        let ca = Ca::new(&dir, "cluster");
+        let n1 = ca.issue("n1", "1", &[]);
+        //节点2拿着签发给节点3的证书
+        let n3 = ca.issue("n3", "3", &["3"]);
+        let s1 = common::server(conf("1", &[("2", 19012)], 19011, n1)).await;
+        let s2 = common::server(conf("2", &[("1", 19011)], 19012, n3)).await;
+        let (m1, m2) = (s1.metrics(), s2.metrics());
+        let (h1, h2) = (s1.start(), s2.start());
+
+        //两个方向都失败: 1连2时发现证书不是2的, 2连1时1拒绝它的握手
+        assert!(common::wait(|| m1.handshake_failures.get() > 0 && m2.handshake_failures.get() > 0).await);
+        assert!(common::wait(|| !connected(&m1, "2") && !connected(&m2, "1")).await);
+
+        h1.shutdown(false).await.unwrap();
+        h2.shutdown(false).await.unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+    });
+}